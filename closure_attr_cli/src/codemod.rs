@@ -0,0 +1,388 @@
+//! `closure-attr-codemod` rewrites blocks which prepare captures by hand,
+//! e.g.
+//!
+//! ```ignore
+//! {
+//!     let a = a.clone();
+//!     let b = Rc::downgrade(&b);
+//!     move || {
+//!         let Some(b) = b.upgrade() else { return };
+//!         ...
+//!     }
+//! }
+//! ```
+//!
+//! into `#[closure(clone a, fail(()) b)] move || { ... }`, and adds
+//! `#[closure_attr::with_closure]` to the enclosing fn. Only the rewritten
+//! text changes; the rest of the file keeps its formatting.
+
+mod rewrite;
+
+use std::{collections::BTreeMap, process::ExitCode};
+
+use closure_attr_core::source::Text;
+use proc_macro2::{LineColumn, Span};
+use syn::{
+    spanned::Spanned, visit::Visit, Attribute, Block, Expr, ExprBlock, ImplItemFn, ItemFn, Local,
+    LocalInit, Pat, PatIdent, Path, Signature, Stmt, Visibility,
+};
+
+const USAGE: &str = "\
+usage: closure-attr-codemod [--dry-run] [FILE...]
+
+Rewrites blocks which clone, borrow, or downgrade variables before a move
+closure into #[closure(...)] closures, in place. Reads stdin and writes
+stdout if no FILE is given.
+
+  --dry-run  print a diff instead of writing";
+
+fn main() -> ExitCode {
+    rewrite::main(USAGE, rewrite)
+}
+
+/// Rewrites the blocks in `src` which can become `#[closure(...)]` closures.
+fn rewrite(src: &str) -> syn::Result<String> {
+    let file = syn::parse_file(src)?;
+    let mut codemod = Codemod {
+        src,
+        text: Text::new(src),
+        edits: Vec::new(),
+        dedents: BTreeMap::new(),
+        fns: Vec::new(),
+    };
+    codemod.visit_file(&file);
+
+    let mut edits = codemod.edits;
+    for (line_start, n) in codemod.dedents {
+        if edits.iter().any(|e| e.0 <= line_start && line_start < e.1) {
+            continue;
+        }
+        let indent = src[line_start..].len() - src[line_start..].trim_start_matches(' ').len();
+        edits.push((line_start, line_start + n.min(indent), String::new()));
+    }
+    edits.sort_by_key(|e| e.0);
+    let mut out = String::new();
+    let mut pos = 0;
+    for (start, end, text) in edits {
+        if start < pos {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "can't rewrite: edits of nested blocks overlap",
+            ));
+        }
+        out.push_str(&src[pos..start]);
+        out.push_str(&text);
+        pos = end;
+    }
+    out.push_str(&src[pos..]);
+    Ok(out)
+}
+
+/// The fn which encloses the code being visited.
+struct Fn {
+    /// Where to insert `#[closure_attr::with_closure]`.
+    insert_at: usize,
+    has_attr: bool,
+    rewritten: bool,
+}
+
+struct Codemod<'a> {
+    src: &'a str,
+    text: Text<'a>,
+    /// Byte ranges to replace, and their replacements.
+    edits: Vec<(usize, usize, String)>,
+    /// Spaces to remove from the start of lines, by byte offset.
+    dedents: BTreeMap<usize, usize>,
+    fns: Vec<Fn>,
+}
+
+impl Codemod<'_> {
+    fn offset(&self, pos: LineColumn) -> usize {
+        self.text.offset(pos)
+    }
+
+    fn indent(&self, line: usize) -> usize {
+        self.text.indent(line)
+    }
+
+    fn text(&self, node: &impl Spanned) -> &str {
+        let span = node.span();
+        &self.src[self.offset(span.start())..self.offset(span.end())]
+    }
+
+    fn lines(&self, node: &impl Spanned) -> (usize, usize) {
+        let span = node.span();
+        self.text.lines((span.start(), span.end()))
+    }
+
+    fn visit_fn(&mut self, attrs: &[Attribute], vis: &Visibility, sig: &Signature, block: &Block) {
+        let start = match vis {
+            Visibility::Inherited => sig.span().start(),
+            vis => vis.span().start(),
+        };
+        self.fns.push(Fn {
+            insert_at: self.offset(start),
+            has_attr: attrs
+                .iter()
+                .any(|a| a.path().segments.last().unwrap().ident == "with_closure"),
+            rewritten: false,
+        });
+        self.visit_block(block);
+        let f = self.fns.pop().unwrap();
+        if f.rewritten && !f.has_attr {
+            let line = &self.src[..f.insert_at];
+            let indent = &line[line.rfind('\n').map_or(0, |i| i + 1)..];
+            self.edits.push((
+                f.insert_at,
+                f.insert_at,
+                format!("#[closure_attr::with_closure]\n{indent}"),
+            ));
+        }
+    }
+
+    /// Rewrites `block` if it's a run of recognized `let`s followed by a
+    /// `move` closure.
+    fn rewrite_block(&mut self, block: &ExprBlock) -> bool {
+        if !block.attrs.is_empty() || block.label.is_some() || self.fns.is_empty() {
+            return false;
+        }
+        let Some((Stmt::Expr(Expr::Closure(closure), None), lets)) = block.block.stmts.split_last()
+        else {
+            return false;
+        };
+        if lets.is_empty() || !closure.attrs.is_empty() || closure.capture.is_none() {
+            return false;
+        }
+        let mut captures = Vec::new();
+        for stmt in lets {
+            let Some(capture) = self.capture(stmt) else {
+                return false;
+            };
+            captures.push(capture);
+        }
+
+        // Leading upgrades of weak captures become `fail` captures.
+        let mut upgrades = Vec::new();
+        if let Expr::Block(body) = &*closure.body {
+            for stmt in &body.block.stmts {
+                let Some((ident, ret)) = self.upgrade(stmt) else {
+                    break;
+                };
+                let Some(capture) = captures
+                    .iter_mut()
+                    .find(|c| c.kind == "weak" && c.ident == ident)
+                else {
+                    break;
+                };
+                capture.kind = format!("fail({ret})");
+                upgrades.push(stmt);
+            }
+        }
+
+        let captures = captures
+            .iter()
+            .map(|c| match &c.src {
+                Some(src) => format!("{} {} = {src}", c.kind, c.ident),
+                None => format!("{} {}", c.kind, c.ident),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let (block_start, block_end) = (
+            self.offset(block.span().start()),
+            self.offset(block.span().end()),
+        );
+        let (closure_start, closure_end) = (
+            self.offset(closure.span().start()),
+            self.offset(closure.span().end()),
+        );
+        self.edits.push((
+            block_start,
+            closure_start,
+            format!("#[closure({captures})] "),
+        ));
+        for stmt in upgrades {
+            let (start, end) = self.lines(stmt);
+            self.edits.push((start, end, String::new()));
+        }
+        self.edits.push((closure_end, block_end, String::new()));
+
+        // The closure loses the block's level of indentation.
+        let (block_line, closure_line) = (block.span().start().line, closure.span().start().line);
+        let n = self
+            .indent(closure_line)
+            .saturating_sub(self.indent(block_line));
+        if closure_line > block_line && n > 0 {
+            for line in closure_line + 1..=closure.span().end().line {
+                *self.dedents.entry(self.text.line_start(line)).or_default() += n;
+            }
+        }
+        true
+    }
+
+    /// Recognizes `let x = x.clone();`, `let x = &x;`, and
+    /// `let x = Rc::downgrade(&x);`.
+    fn capture(&self, stmt: &Stmt) -> Option<Capture> {
+        let Stmt::Local(Local {
+            attrs,
+            pat:
+                Pat::Ident(PatIdent {
+                    attrs: pat_attrs,
+                    by_ref: None,
+                    mutability,
+                    ident,
+                    subpat: None,
+                }),
+            init:
+                Some(LocalInit {
+                    expr,
+                    diverge: None,
+                    ..
+                }),
+            ..
+        }) = stmt
+        else {
+            return None;
+        };
+        if !attrs.is_empty() || !pat_attrs.is_empty() {
+            return None;
+        }
+        let (kind, src) = match &**expr {
+            Expr::MethodCall(call)
+                if call.method == "clone" && call.args.is_empty() && call.turbofish.is_none() =>
+            {
+                let kind = if mutability.is_some() {
+                    "clone mut"
+                } else {
+                    "clone"
+                };
+                (kind, &*call.receiver)
+            }
+            Expr::Reference(r) if mutability.is_none() => {
+                let kind = if r.mutability.is_some() {
+                    "ref mut"
+                } else {
+                    "ref"
+                };
+                (kind, &*r.expr)
+            }
+            Expr::Call(call) if mutability.is_none() && call.args.len() == 1 => {
+                let Expr::Path(func) = &*call.func else {
+                    return None;
+                };
+                let Some(Expr::Reference(r)) = call.args.first() else {
+                    return None;
+                };
+                if !is_downgrade(&func.path) || r.mutability.is_some() {
+                    return None;
+                }
+                ("weak", &*r.expr)
+            }
+            _ => return None,
+        };
+        let src = self.text(src);
+        Some(Capture {
+            kind: kind.to_string(),
+            ident: ident.to_string(),
+            src: (*src != *ident.to_string()).then(|| src.to_string()),
+        })
+    }
+
+    /// Recognizes `let Some(x) = x.upgrade() else { return <expr> };`,
+    /// returning `x` and the returned expression.
+    fn upgrade(&self, stmt: &Stmt) -> Option<(String, String)> {
+        let Stmt::Local(Local {
+            pat: Pat::TupleStruct(pat),
+            init:
+                Some(LocalInit {
+                    expr,
+                    diverge: Some((_, diverge)),
+                    ..
+                }),
+            ..
+        }) = stmt
+        else {
+            return None;
+        };
+        let Some(Pat::Ident(PatIdent {
+            by_ref: None,
+            mutability: None,
+            ident,
+            subpat: None,
+            ..
+        })) = pat.elems.first()
+        else {
+            return None;
+        };
+        if !pat.path.is_ident("Some") || pat.elems.len() != 1 {
+            return None;
+        }
+        let Expr::MethodCall(call) = &**expr else {
+            return None;
+        };
+        let Expr::Path(receiver) = &*call.receiver else {
+            return None;
+        };
+        if call.method != "upgrade" || !call.args.is_empty() || !receiver.path.is_ident(ident) {
+            return None;
+        }
+        let Expr::Block(diverge) = &**diverge else {
+            return None;
+        };
+        let ret = match diverge.block.stmts.as_slice() {
+            [Stmt::Expr(Expr::Return(ret), _)] => ret,
+            _ => return None,
+        };
+        let ret = ret.expr.as_ref().map_or("()", |e| self.text(e));
+        Some((ident.to_string(), ret.to_string()))
+    }
+}
+
+/// A recognized capture, as `#[closure(...)]` text.
+struct Capture {
+    kind: String,
+    ident: String,
+    /// The captured expression, if it isn't `ident`.
+    src: Option<String>,
+}
+
+/// Whether `path` is `Rc::downgrade` or `Arc::downgrade`, possibly
+/// qualified by `std` or `alloc`, which `weak` captures also support. Other
+/// `downgrade` functions, e.g. glib's `ObjectExt::downgrade`, don't
+/// implement `closure_attr::Downgrade`.
+fn is_downgrade(path: &Path) -> bool {
+    let names = path
+        .segments
+        .iter()
+        .map(|s| s.ident.to_string())
+        .collect::<Vec<_>>();
+    let names = names.iter().map(String::as_str).collect::<Vec<_>>();
+    match names.as_slice() {
+        ["Rc", "downgrade"] | ["Arc", "downgrade"] => path.leading_colon.is_none(),
+        ["std" | "alloc", "rc", "Rc", "downgrade"]
+        | ["std" | "alloc", "sync", "Arc", "downgrade"] => true,
+        _ => false,
+    }
+}
+
+impl<'ast> Visit<'ast> for Codemod<'_> {
+    fn visit_item_fn(&mut self, f: &'ast ItemFn) {
+        self.visit_fn(&f.attrs, &f.vis, &f.sig, &f.block);
+    }
+
+    fn visit_impl_item_fn(&mut self, f: &'ast ImplItemFn) {
+        self.visit_fn(&f.attrs, &f.vis, &f.sig, &f.block);
+    }
+
+    fn visit_expr_block(&mut self, block: &'ast ExprBlock) {
+        if self.rewrite_block(block) {
+            self.fns.last_mut().unwrap().rewritten = true;
+            // The closure's body may hold more blocks to rewrite.
+            let Some(Stmt::Expr(Expr::Closure(closure), _)) = block.block.stmts.last() else {
+                unreachable!();
+            };
+            self.visit_expr_closure(closure);
+        } else {
+            syn::visit::visit_expr_block(self, block);
+        }
+    }
+}
@@ -0,0 +1,19 @@
+//! `closure-attr-eject` rewrites `#[closure(...)]` closures into plain Rust
+//! and removes `#[with_closure]`, so a crate no longer needs the macros.
+
+mod rewrite;
+
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+usage: closure-attr-eject [--dry-run] [FILE...]
+
+Rewrites the #[closure(...)] closures in #[with_closure] items into plain
+Rust, in place, and removes #[with_closure]. Reads stdin and writes stdout
+if no FILE is given.
+
+  --dry-run  print a diff instead of writing";
+
+fn main() -> ExitCode {
+    rewrite::main(USAGE, closure_attr_core::eject)
+}
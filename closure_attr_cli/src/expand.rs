@@ -0,0 +1,278 @@
+//! `closure-attr-expand` prints what the `#[with_closure]` items in a file
+//! expand to, with the original lines of each expanded item as comments.
+
+use std::{io::Read, process::ExitCode};
+
+use closure_attr_core::source::is_with_closure;
+use proc_macro2::{LineColumn, TokenStream, TokenTree};
+use quote::ToTokens;
+use serde_json::{json, Value};
+use syn::{
+    spanned::Spanned,
+    visit::Visit,
+    visit_mut::{self, VisitMut},
+    Attribute, ExprClosure, ImplItemFn, Item, Meta,
+};
+
+const USAGE: &str = "\
+usage: closure-attr-expand [--json] [FILE]
+
+Reads FILE, or stdin if FILE is omitted or -, and prints what its
+#[with_closure] items expand to.
+
+  --json  list the #[closure(...)] closures in #[with_closure] items instead";
+
+fn main() -> ExitCode {
+    let mut json = false;
+    let mut path = None;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => json = true,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            _ if path.is_none() && (arg == "-" || !arg.starts_with('-')) => path = Some(arg),
+            _ => {
+                eprintln!("{USAGE}");
+                return ExitCode::from(2);
+            }
+        }
+    }
+
+    let (name, src) = match path.as_deref() {
+        None | Some("-") => {
+            let mut src = String::new();
+            if let Err(e) = std::io::stdin().read_to_string(&mut src) {
+                eprintln!("error: stdin: {e}");
+                return ExitCode::FAILURE;
+            }
+            ("<stdin>", src)
+        }
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(src) => (path, src),
+            Err(e) => {
+                eprintln!("error: {path}: {e}");
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+    let file = match syn::parse_file(&src) {
+        Ok(file) => file,
+        Err(e) => {
+            report(name, e.span().start(), &e.to_string());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if json {
+        let mut closures = Closures::default();
+        closures.visit_file(&file);
+        println!("{:#}", Value::Array(closures.found));
+        return ExitCode::SUCCESS;
+    }
+
+    let (out, errors) = expand(&src, file);
+    print!("{out}");
+    for (pos, msg) in &errors {
+        report(name, *pos, msg);
+    }
+    if errors.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Prints a diagnostic in the compiler's `file:line:column` format.
+fn report(name: &str, pos: LineColumn, msg: &str) {
+    eprintln!("{name}:{}:{}: error: {msg}", pos.line, pos.column + 1);
+}
+
+/// Pretty-prints `file` with its `#[with_closure]` items expanded. Each
+/// top-level item which contains one is preceded by its original lines as
+/// comments. Also returns the errors the expansion reported.
+fn expand(src: &str, file: syn::File) -> (String, Vec<(LineColumn, String)>) {
+    let lines = src.lines().collect::<Vec<_>>();
+    let mut expander = Expander::default();
+    let mut out = prettyplease::unparse(&syn::File {
+        items: Vec::new(),
+        ..file.clone()
+    });
+    for mut item in file.items {
+        let (start, end) = (item.span().start().line, item.span().end().line);
+        expander.expanded = false;
+        expander.visit_item_mut(&mut item);
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        if expander.expanded {
+            for line in &lines[start - 1..end] {
+                out.push_str(format!("// {line}").trim_end());
+                out.push('\n');
+            }
+        }
+        out.push_str(&prettyplease::unparse(&syn::File {
+            shebang: None,
+            attrs: Vec::new(),
+            items: vec![item],
+        }));
+    }
+    (out, expander.errors)
+}
+
+/// Expands `#[with_closure]` items, wherever they're nested.
+#[derive(Default)]
+struct Expander {
+    expanded: bool,
+    errors: Vec<(LineColumn, String)>,
+}
+
+impl Expander {
+    /// Runs `with_closure` on `tokens`, the item `attr` was removed from.
+    /// Returns the expanded item; its errors are collected.
+    fn with_closure(&mut self, attr: Attribute, tokens: TokenStream) -> Item {
+        let args = match attr.meta {
+            Meta::List(list) => list.tokens,
+            _ => TokenStream::new(),
+        };
+        let out = closure_attr_core::with_closure(args, tokens);
+        let file = syn::parse2::<syn::File>(out).expect("with_closure returned an item");
+        let mut items = Vec::new();
+        for item in file.items {
+            match item {
+                Item::Macro(m) if is_compile_error(&m.mac.path) => {
+                    let msg = syn::parse2::<syn::LitStr>(m.mac.tokens.clone())
+                        .map_or_else(|_| m.mac.tokens.to_string(), |lit| lit.value());
+                    self.errors.push((m.mac.tokens.span().start(), msg));
+                }
+                item => items.push(item),
+            }
+        }
+        self.expanded = true;
+        items.pop().expect("with_closure returned an item")
+    }
+}
+
+fn is_compile_error(path: &syn::Path) -> bool {
+    path.segments
+        .last()
+        .is_some_and(|s| s.ident == "compile_error")
+}
+
+/// Removes the `#[with_closure]` attribute from `attrs`, if present.
+fn take_with_closure(attrs: &mut Vec<Attribute>) -> Option<Attribute> {
+    let pos = attrs.iter().position(is_with_closure)?;
+    Some(attrs.remove(pos))
+}
+
+impl VisitMut for Expander {
+    fn visit_item_mut(&mut self, item: &mut Item) {
+        let attrs = match item {
+            Item::Const(i) => &mut i.attrs,
+            Item::Enum(i) => &mut i.attrs,
+            Item::Fn(i) => &mut i.attrs,
+            Item::Impl(i) => &mut i.attrs,
+            Item::Mod(i) => &mut i.attrs,
+            Item::Static(i) => &mut i.attrs,
+            Item::Struct(i) => &mut i.attrs,
+            Item::Trait(i) => &mut i.attrs,
+            _ => return visit_mut::visit_item_mut(self, item),
+        };
+        if let Some(attr) = take_with_closure(attrs) {
+            *item = self.with_closure(attr, item.to_token_stream());
+        }
+        // Like the compiler, expand nested items after the outer one.
+        visit_mut::visit_item_mut(self, item);
+    }
+
+    fn visit_impl_item_fn_mut(&mut self, f: &mut ImplItemFn) {
+        let Some(attr) = take_with_closure(&mut f.attrs) else {
+            return visit_mut::visit_impl_item_fn_mut(self, f);
+        };
+        let Item::Fn(item) = self.with_closure(attr, f.to_token_stream()) else {
+            unreachable!("with_closure preserves the item kind");
+        };
+        f.attrs = item.attrs;
+        f.vis = item.vis;
+        f.sig = item.sig;
+        f.block = *item.block;
+        visit_mut::visit_impl_item_fn_mut(self, f);
+    }
+}
+
+/// Lists the `#[closure(...)]` closures within `#[with_closure]` items.
+#[derive(Default)]
+struct Closures {
+    depth: usize,
+    found: Vec<Value>,
+}
+
+impl Closures {
+    fn within(&mut self, attrs: &[Attribute], visit: impl FnOnce(&mut Self)) {
+        let inside = attrs.iter().any(is_with_closure);
+        self.depth += usize::from(inside);
+        visit(self);
+        self.depth -= usize::from(inside);
+    }
+}
+
+impl<'a> Visit<'a> for Closures {
+    fn visit_item(&mut self, item: &'a Item) {
+        let attrs = match item {
+            Item::Const(i) => &i.attrs[..],
+            Item::Enum(i) => &i.attrs,
+            Item::Fn(i) => &i.attrs,
+            Item::Impl(i) => &i.attrs,
+            Item::Mod(i) => &i.attrs,
+            Item::Static(i) => &i.attrs,
+            Item::Struct(i) => &i.attrs,
+            Item::Trait(i) => &i.attrs,
+            _ => &[],
+        };
+        self.within(attrs, |v| syn::visit::visit_item(v, item));
+    }
+
+    fn visit_impl_item_fn(&mut self, f: &'a ImplItemFn) {
+        self.within(&f.attrs, |v| syn::visit::visit_impl_item_fn(v, f));
+    }
+
+    fn visit_expr_closure(&mut self, closure: &'a ExprClosure) {
+        let attr = closure.attrs.iter().find(|a| a.path().is_ident("closure"));
+        if let (Some(attr), true) = (attr, self.depth > 0) {
+            let captures = match &attr.meta {
+                Meta::List(list) => split_commas(list.tokens.clone()),
+                _ => Vec::new(),
+            };
+            let (start, end) = (closure.span().start(), closure.span().end());
+            self.found.push(json!({
+                "start": {"line": start.line, "column": start.column + 1},
+                "end": {"line": end.line, "column": end.column + 1},
+                "captures": captures,
+            }));
+        }
+        syn::visit::visit_expr_closure(self, closure);
+    }
+}
+
+/// Splits `tokens` at its top-level commas, returning each part's source.
+fn split_commas(tokens: TokenStream) -> Vec<String> {
+    let mut parts = vec![Vec::new()];
+    for tt in tokens {
+        match &tt {
+            TokenTree::Punct(p) if p.as_char() == ',' => parts.push(Vec::new()),
+            _ => parts.last_mut().unwrap().push(tt),
+        }
+    }
+    parts
+        .into_iter()
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let (first, last) = (part[0].span(), part[part.len() - 1].span());
+            first
+                .join(last)
+                .and_then(|span| span.source_text())
+                .unwrap_or_else(|| part.into_iter().collect::<TokenStream>().to_string())
+        })
+        .collect()
+}
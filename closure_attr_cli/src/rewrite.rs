@@ -0,0 +1,92 @@
+//! The driver shared by tools which rewrite source files.
+
+use std::{io::Read, process::ExitCode};
+
+use similar::TextDiff;
+
+/// Runs a tool which rewrites files in place, or stdin to stdout.
+pub fn main(usage: &str, rewrite: fn(&str) -> syn::Result<String>) -> ExitCode {
+    let mut dry_run = false;
+    let mut paths = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "-h" | "--help" => {
+                println!("{usage}");
+                return ExitCode::SUCCESS;
+            }
+            _ if !arg.starts_with('-') => paths.push(arg),
+            _ => {
+                eprintln!("{usage}");
+                return ExitCode::from(2);
+            }
+        }
+    }
+
+    if paths.is_empty() {
+        let mut src = String::new();
+        if let Err(e) = std::io::stdin().read_to_string(&mut src) {
+            eprintln!("error: stdin: {e}");
+            return ExitCode::FAILURE;
+        }
+        return match rewrite(&src) {
+            Ok(out) if dry_run => {
+                print!("{}", diff("<stdin>", &src, &out));
+                ExitCode::SUCCESS
+            }
+            Ok(out) => {
+                print!("{out}");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                report("<stdin>", &e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let mut status = ExitCode::SUCCESS;
+    for path in &paths {
+        let src = match std::fs::read_to_string(path) {
+            Ok(src) => src,
+            Err(e) => {
+                eprintln!("error: {path}: {e}");
+                status = ExitCode::FAILURE;
+                continue;
+            }
+        };
+        let out = match rewrite(&src) {
+            Ok(out) => out,
+            Err(e) => {
+                report(path, &e);
+                status = ExitCode::FAILURE;
+                continue;
+            }
+        };
+        if out == src {
+            continue;
+        }
+        if dry_run {
+            print!("{}", diff(path, &src, &out));
+        } else if let Err(e) = std::fs::write(path, out) {
+            eprintln!("error: {path}: {e}");
+            status = ExitCode::FAILURE;
+        }
+    }
+    status
+}
+
+/// Prints diagnostics in the compiler's `file:line:column` format.
+fn report(name: &str, errors: &syn::Error) {
+    for e in errors {
+        let pos = e.span().start();
+        eprintln!("{name}:{}:{}: error: {e}", pos.line, pos.column + 1);
+    }
+}
+
+fn diff(name: &str, old: &str, new: &str) -> String {
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .header(&format!("a/{name}"), &format!("b/{name}"))
+        .to_string()
+}
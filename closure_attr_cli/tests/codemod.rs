@@ -0,0 +1,133 @@
+use std::{
+    io::Write,
+    process::{Command, Output, Stdio},
+};
+
+fn run(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_closure-attr-codemod"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+const SRC: &str = "\
+fn f(a: Rc<u8>, b: Rc<u8>) {
+    spawn({
+        let a = a.clone();
+        let b = Rc::downgrade(&b);
+        let r = &self.r;
+        move || {
+            let Some(b) = b.upgrade() else { return };
+            run(a, b, r);
+        }
+    });
+}
+";
+
+#[test]
+fn rewrite() {
+    let out = run(&[], SRC);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "\
+#[closure_attr::with_closure]
+fn f(a: Rc<u8>, b: Rc<u8>) {
+    spawn(#[closure(clone a, fail(()) b, ref r = self.r)] move || {
+        run(a, b, r);
+    });
+}
+"
+    );
+
+    // Nested blocks are rewritten too; existing attributes are kept.
+    let out = run(
+        &[],
+        "\
+impl S {
+    #[with_closure]
+    fn f(&self) {
+        let f = {
+            let mut a = a.clone();
+            let w = std::sync::Arc::downgrade(&w);
+            move || {
+                let Some(w) = w.upgrade() else {
+                    return 7;
+                };
+                {
+                    let a = &a;
+                    move || a
+                }
+            }
+        };
+    }
+}
+",
+    );
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "\
+impl S {
+    #[with_closure]
+    fn f(&self) {
+        let f = #[closure(clone mut a, fail(7) w)] move || {
+            #[closure(ref a)] move || a
+        };
+    }
+}
+"
+    );
+}
+
+#[test]
+fn unchanged() {
+    for src in [
+        // Other statements.
+        "fn f() { let _ = { let a = a.clone(); g(); move || a }; }",
+        // Not a move closure.
+        "fn f() { let _ = { let a = a.clone(); || a }; }",
+        // A `mut` binding of a reference.
+        "fn f() { let _ = { let mut a = &a; move || a }; }",
+        // A downgrade other than `Rc`'s or `Arc`'s.
+        "fn f() { let _ = { let a = ObjectExt::downgrade(&a); move || a }; }",
+        // Not in a fn.
+        "static S: X = { let a = a.clone(); move || a };",
+    ] {
+        let out = run(&[], src);
+        assert_eq!(String::from_utf8(out.stdout).unwrap(), src);
+    }
+}
+
+#[test]
+fn files() {
+    let path = std::env::temp_dir().join(format!("closure-attr-codemod-{}.rs", std::process::id()));
+    std::fs::write(&path, SRC).unwrap();
+    let path_str = path.to_str().unwrap();
+
+    let out = run(&["--dry-run", path_str], "");
+    assert!(out.status.success());
+    let diff = String::from_utf8(out.stdout).unwrap();
+    assert!(diff.starts_with(&format!("--- a/{path_str}\n+++ b/{path_str}\n")));
+    assert!(diff.contains("\n+#[closure_attr::with_closure]\n"));
+    assert!(diff.contains("\n-        let a = a.clone();\n"));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), SRC);
+
+    let out = run(&[path_str], "");
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        String::from_utf8(run(&[], SRC).stdout).unwrap()
+    );
+    std::fs::remove_file(&path).unwrap();
+}
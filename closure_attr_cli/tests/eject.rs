@@ -0,0 +1,286 @@
+use std::{
+    io::Write,
+    process::{Command, Output, Stdio},
+};
+
+fn run(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_closure-attr-eject"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn eject() {
+    let out = run(
+        &[],
+        "\
+use closure_attr::with_closure;
+use std::rc::Rc;
+
+#[with_closure]
+fn f(a: Rc<u8>, b: Rc<u8>, p: Point) {
+    // Kept.
+    let c = #[closure(clone a, fail(0) b)]
+    move || {
+        // Also kept.
+        *a + *b
+    };
+    spawn(#[closure(ref p)] move || p.x);
+    let d = #[closure(move a)] move || a.len();
+    let e = #[closure(ref p)] move || println!(\"{p:?}\");
+}
+",
+    );
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "\
+use std::rc::Rc;
+
+fn f(a: Rc<u8>, b: Rc<u8>, p: Point) {
+    // Kept.
+    let c = {
+        let a = a.clone();
+        let b = closure_attr::Downgrade::downgrade(&b);
+        move || {
+            let Some(b) = closure_attr::Upgrade::upgrade(&b) else {
+                return 0;
+            };
+            // Also kept.
+            *a + *b
+        }
+    };
+    spawn({
+        let p = &p;
+        move || {
+            let _ = &p;
+            p.x
+        }
+    });
+    let d = {
+        let a = a;
+        move || a.len()
+    };
+    let e = {
+        let p = &p;
+        move || println!(\"{p:?}\")
+    };
+}
+"
+    );
+}
+
+#[test]
+fn nested() {
+    let out = run(
+        &[],
+        "\
+impl S {
+    #[closure_attr::with_closure]
+    fn f(&self, a: Rc<u8>) {
+        let f = #[closure(clone a)]
+        move || {
+            let g = #[closure(inherit)] move || a.len();
+            g()
+        };
+    }
+}
+",
+    );
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "\
+impl S {
+    fn f(&self, a: Rc<u8>) {
+        let f = {
+            let a = a.clone();
+            move || {
+                let g = {
+                    let a = a.clone();
+                    move || a.len()
+                };
+                g()
+            }
+        };
+    }
+}
+"
+    );
+}
+
+#[test]
+fn nested_same_line() {
+    let out = run(
+        &[],
+        "\
+#[closure_attr::with_closure]
+fn f(a: Rc<u8>, b: Rc<u8>) {
+    let c = #[closure(clone a)] move || #[closure(clone b)] move || *a + *b;
+    let d = #[closure(panic a)] move || #[closure(clone b)] move || *a + *b;
+}
+",
+    );
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "\
+fn f(a: Rc<u8>, b: Rc<u8>) {
+    let c = {
+        let a = a.clone();
+        move || {
+            let b = b.clone();
+            move || *a + *b
+        }
+    };
+    let d = {
+        let a = closure_attr::Downgrade::downgrade(&a);
+        move || {
+            let Some(a) = closure_attr::Upgrade::upgrade(&a) else {
+                panic!(\"Closure failed to upgrade weak pointer\");
+            };
+            {
+                let b = b.clone();
+                move || *a + *b
+            }
+        }
+    };
+}
+"
+    );
+}
+
+#[test]
+fn errors() {
+    let out = run(
+        &[],
+        "\
+#[with_closure]
+fn f() {
+    let c = #[closure(clone a)] move || a;
+    let d = #[closure(frob b)] move || b;
+}
+",
+    );
+    assert!(!out.status.success());
+    assert!(out.stdout.is_empty());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert_eq!(stderr.lines().count(), 1, "{stderr}");
+    assert!(stderr.starts_with("<stdin>:4:23:"), "{stderr}");
+}
+
+#[test]
+fn auto_move() {
+    let out = run(
+        &[],
+        "#[with_closure(auto_move)]
+fn f() {
+    let c = #[closure(clone a)] |x| a + x;
+}
+",
+    );
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "fn f() {
+    let c = {
+        let a = a.clone();
+        move |x| a + x
+    };
+}
+"
+    );
+}
+
+#[test]
+fn non_move() {
+    let out = run(
+        &[],
+        "#[with_closure]
+fn f(v: Vec<u8>) {
+    let c = #[closure(clone a)] |x| a + x + v.len();
+}
+",
+    );
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "fn f(v: Vec<u8>) {
+    let c = {
+        let v = &v;
+        let a = a.clone();
+        move |x| a + x + v.len()
+    };
+}
+"
+    );
+}
+
+#[test]
+fn assert() {
+    let out = run(
+        &[],
+        "#[with_closure]
+fn f(a: Arc<u8>) {
+    spawn(#[closure(clone a, assert(Send + 'static + FnOnce()))] move || {
+        drop(a);
+    });
+}
+",
+    );
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "fn f(a: Arc<u8>) {
+    spawn({
+        let a = a.clone();
+        fn assert_closure<F: Send + 'static + FnOnce()>(f: F) -> F {
+            f
+        }
+        assert_closure(move || {
+            drop(a);
+        })
+    });
+}
+"
+    );
+}
+
+#[test]
+fn assert_generic() {
+    let out = run(
+        &[],
+        "#[with_closure]
+fn f<T: Clone + Send + 'static>(t: T) {
+    spawn(#[closure(clone t, assert(Send + FnOnce() -> T))] move || t);
+}
+",
+    );
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "fn f<T: Clone + Send + 'static>(t: T) {
+    spawn({
+        let t = t.clone();
+        fn assert_closure<F: Send>(f: F) -> F {
+            f
+        }
+        let closure = assert_closure(move || t);
+        let _: &dyn FnOnce() -> T = &closure;
+        closure
+    });
+}
+"
+    );
+}
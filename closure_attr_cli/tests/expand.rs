@@ -0,0 +1,98 @@
+use std::{
+    io::Write,
+    process::{Command, Output, Stdio},
+};
+
+fn run(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_closure-attr-expand"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+const SRC: &str = "\
+fn untouched() {}
+
+#[closure_attr::with_closure]
+fn f() {
+    #[closure(clone a, ref b)] move || ();
+}
+";
+
+#[test]
+fn expand() {
+    let out = run(&[], SRC);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "\
+fn untouched() {}
+
+// #[closure_attr::with_closure]
+// fn f() {
+//     #[closure(clone a, ref b)] move || ();
+// }
+fn f() {
+    {
+        let a = a.clone();
+        let b = &b;
+        move || {
+            #[allow(unreachable_code, clippy::never_loop)]
+            loop {
+                break;
+                let _ = &a;
+                let _ = &b;
+            }
+            ()
+        }
+    };
+}
+"
+    );
+}
+
+#[test]
+fn errors() {
+    let out = run(
+        &["-"],
+        "#[with_closure]\nfn f() {\n    #[closure(clone a, frob b)] move || ();\n}\n",
+    );
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(out.stderr).unwrap(),
+        "<stdin>:3:24: error: expected clone, clone mut, ref, ref mut, move, move mut, \
+         weak, upgrade, fail, panic, enter, using, to_owned, into, via, try_clone, or share (2)\n"
+    );
+
+    let out = run(&[], "fn f(");
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8(out.stdout).unwrap().is_empty());
+
+    let out = run(&["--bogus"], "");
+    assert_eq!(out.status.code(), Some(2));
+}
+
+#[test]
+fn json() {
+    let out = run(&["--json"], SRC);
+    assert!(out.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!([{
+            "start": {"line": 5, "column": 5},
+            "end": {"line": 5, "column": 42},
+            "captures": ["clone a", "ref b"],
+        }])
+    );
+}
@@ -0,0 +1,583 @@
+//! Finding the variables a closure's body names: those a non-`move` closure
+//! borrows, and captures which it shadows.
+
+use proc_macro2::{Span, TokenStream as TokenStream2, TokenTree};
+use quote::ToTokens;
+use syn::{
+    parse::{ParseStream, Parser},
+    parse_quote_spanned,
+    punctuated::Punctuated,
+    visit::Visit,
+    visit_mut::VisitMut,
+    BinOp, Block, Expr, ExprClosure, ExprGroup, ExprLit, ExprParen, Ident, Lit, LitStr, LocalInit,
+    Macro, Pat, PatIdent, Stmt, Token,
+};
+
+/// The variables which `closure`'s body names, other than `bound`, in order
+/// of first use, and whether each is borrowed mutably. A non-`move` closure
+/// would borrow these. Variables whose values the body moves or copies out,
+/// rather than only using them in place, are left out: a `move` closure
+/// moves or copies them as a non-`move` closure would.
+///
+/// Without name resolution this is a guess: it takes every single-identifier
+/// path which starts with a lowercase letter, and which isn't bound within
+/// the closure where it's used, to be a variable. Macro arguments are
+/// searched if they parse as expressions, or as patterns as in `matches!`;
+/// otherwise any identifier among their tokens counts. So do `{name}`
+/// arguments in format strings. A variable in `mutable`, those declared
+/// `mut`, is borrowed mutably if the closure assigns to it, takes `&mut` of
+/// it, or calls a method on it.
+pub(crate) fn borrowed(
+    closure: &ExprClosure,
+    bound: &[Ident],
+    mutable: &[Ident],
+) -> Vec<(Ident, bool)> {
+    let names = names(|n| n.closure(&mut closure.clone()));
+    let mut borrowed = Vec::<(Ident, bool)>::new();
+    for ident in &names.used {
+        if bound.contains(ident) || borrowed.iter().any(|(i, _)| i == ident) {
+            continue;
+        }
+        let mutably = names.mutated.contains(ident) && mutable.contains(ident);
+        if mutably || !names.moved.contains(ident) {
+            borrowed.push((ident.clone(), mutably));
+        }
+    }
+    borrowed
+}
+
+/// A use of a variable which [deref] rewrote to `*name`, or to `(*name)`
+/// if `parens`. Ejecting makes the same edits to the source.
+#[cfg_attr(not(feature = "eject"), allow(dead_code))]
+pub(crate) struct Deref {
+    pub(crate) span: Span,
+    pub(crate) parens: bool,
+}
+
+/// Rewrites the uses of `idents` in `closure`'s body, which are now
+/// references to the variables, to dereference them. Method calls, field
+/// accesses, indexing, calls, and `match` dereference on their own, so are
+/// left alone.
+pub(crate) fn deref(closure: &mut ExprClosure, idents: &[Ident]) -> Vec<Deref> {
+    let mut names = Names {
+        deref: idents,
+        ..Names::default()
+    };
+    names.closure(closure);
+    names.done
+}
+
+/// Where `closure` binds `ident` before using it, if it does: in a parameter,
+/// or in a `let` at the top level of its body. Returns a description of the
+/// binding. A capture of `ident` would have no effect. A macro which has
+/// `ident` among its tokens counts as a use.
+pub(crate) fn shadowing(closure: &ExprClosure, ident: &Ident) -> Option<&'static str> {
+    for input in &closure.inputs {
+        if bindings(input).contains(ident) {
+            return Some("a parameter");
+        }
+    }
+    let Expr::Block(block) = &*closure.body else {
+        return None;
+    };
+    for stmt in &block.block.stmts {
+        match stmt {
+            Stmt::Local(local) => {
+                if let Some(init) = &local.init {
+                    if names(|n| n.local_init(&mut init.clone())).uses(ident) {
+                        return None;
+                    }
+                }
+                if bindings(&local.pat).contains(ident) {
+                    return Some("a `let`");
+                }
+            }
+            stmt => {
+                if names(|n| n.visit_stmt_mut(&mut stmt.clone())).uses(ident) {
+                    return None;
+                }
+            }
+        }
+    }
+    None
+}
+
+/// The arguments of `mac`, if they all parse as expressions.
+pub(crate) fn macro_args(mac: &Macro) -> Option<Punctuated<Expr, Token![,]>> {
+    Punctuated::parse_terminated.parse2(mac.tokens.clone()).ok()
+}
+
+/// Whether `mac` uses `ident`, searching it as [borrowed] does.
+#[cfg_attr(not(feature = "eject"), allow(dead_code))]
+pub(crate) fn macro_uses(mac: &Macro, ident: &Ident) -> bool {
+    names(|n| n.visit_macro_mut(&mut mac.clone()))
+        .used
+        .contains(ident)
+}
+
+/// Whether `arg`, an argument of a macro, uses `ident`: a string literal
+/// uses its `{name}` format arguments.
+#[cfg_attr(not(feature = "eject"), allow(dead_code))]
+pub(crate) fn macro_arg_uses(arg: &Expr, ident: &Ident) -> bool {
+    names(|n| n.arg(&mut arg.clone())).used.contains(ident)
+}
+
+fn names<'a>(visit: impl FnOnce(&mut Names<'a>)) -> Names<'a> {
+    let mut names = Names::default();
+    visit(&mut names);
+    names
+}
+
+/// The variables `pat` binds.
+fn bindings(pat: &Pat) -> Vec<Ident> {
+    struct Idents(Vec<Ident>);
+
+    impl<'ast> Visit<'ast> for Idents {
+        fn visit_pat_ident(&mut self, pat: &'ast PatIdent) {
+            self.0.push(pat.ident.clone());
+            syn::visit::visit_pat_ident(self, pat);
+        }
+    }
+
+    let mut idents = Idents(Vec::new());
+    idents.visit_pat(pat);
+    idents.0
+}
+
+/// How an expression is used.
+#[derive(Clone, Copy, PartialEq)]
+enum Context {
+    /// Its value is moved or copied out.
+    Value,
+    /// As the operand of `?` or `.await`, which would apply before a
+    /// dereference. Otherwise like `Value`.
+    Postfix,
+    /// As a place: borrowed, compared, assigned to, or passed to a macro.
+    Place,
+    /// As a place which dereferences on its own: a method receiver, the
+    /// base of a field access or indexing, a callee, or a scrutinee, whose
+    /// patterns bind by reference through one.
+    Auto,
+}
+
+/// Walks a closure, finding the variables from outside it which it uses,
+/// and rewriting the uses of `deref`.
+#[derive(Default)]
+struct Names<'a> {
+    /// The variables bound in each enclosing scope within the closure.
+    scopes: Vec<Vec<Ident>>,
+    used: Vec<Ident>,
+    /// Variables assigned to, borrowed with `&mut`, or called a method on.
+    mutated: Vec<Ident>,
+    /// Variables whose values are moved or copied out.
+    moved: Vec<Ident>,
+    /// Every identifier among macro tokens.
+    mentioned: Vec<Ident>,
+    deref: &'a [Ident],
+    done: Vec<Deref>,
+}
+
+impl Names<'_> {
+    fn uses(&self, ident: &Ident) -> bool {
+        self.used.contains(ident) || self.mentioned.contains(ident)
+    }
+
+    /// Whether `ident` could name a variable from outside the closure.
+    fn outer(&self, ident: &Ident) -> bool {
+        let name = ident.to_string();
+        name != "self"
+            && name.starts_with(|c: char| c.is_lowercase() || c == '_')
+            && !self.scopes.iter().any(|scope| scope.contains(ident))
+    }
+
+    fn scoped(&mut self, visit: impl FnOnce(&mut Self)) {
+        self.scopes.push(Vec::new());
+        visit(self);
+        self.scopes.pop();
+    }
+
+    /// Binds the variables `pat` binds in the innermost scope.
+    fn bind(&mut self, pat: &Pat) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.extend(bindings(pat));
+        }
+    }
+
+    fn closure(&mut self, closure: &mut ExprClosure) {
+        self.scoped(|n| {
+            for input in &closure.inputs {
+                n.bind(input);
+            }
+            n.child(&mut closure.body, Context::Value);
+        });
+    }
+
+    fn local_init(&mut self, init: &mut LocalInit) {
+        self.child(&mut init.expr, Context::Value);
+        if let Some((_, diverge)) = &mut init.diverge {
+            self.child(diverge, Context::Value);
+        }
+    }
+
+    fn mention(&mut self, tokens: TokenStream2) {
+        for token in tokens {
+            match token {
+                TokenTree::Group(group) => self.mention(group.stream()),
+                TokenTree::Ident(ident) => self.mentioned.push(ident),
+                _ => {}
+            }
+        }
+    }
+
+    fn use_name(&mut self, ident: Ident) {
+        if self.outer(&ident) {
+            self.used.push(ident);
+        }
+    }
+
+    fn mutate(&mut self, expr: &Expr) {
+        if let Some(ident) = place(expr) {
+            if self.outer(ident) {
+                self.mutated.push(ident.clone());
+            }
+        }
+    }
+
+    /// Visits `expr`, which is used as `context` says.
+    fn child(&mut self, expr: &mut Expr, context: Context) {
+        match expr {
+            Expr::Paren(ExprParen { expr: inner, .. })
+            | Expr::Group(ExprGroup { expr: inner, .. }) => {
+                let context = match context {
+                    Context::Postfix => Context::Value,
+                    context => context,
+                };
+                self.child(inner, context);
+            }
+            Expr::Path(path) if path.qself.is_none() => {
+                let Some(ident) = path.path.get_ident().cloned() else {
+                    return;
+                };
+                if !self.outer(&ident) {
+                    return;
+                }
+                self.used.push(ident.clone());
+                if matches!(context, Context::Value | Context::Postfix) {
+                    self.moved.push(ident.clone());
+                }
+                if context != Context::Auto && self.deref.contains(&ident) {
+                    let span = ident.span();
+                    let parens = context == Context::Postfix;
+                    self.done.push(Deref { span, parens });
+                    *expr = if parens {
+                        parse_quote_spanned! {span=> (*#ident)}
+                    } else {
+                        parse_quote_spanned! {span=> *#ident}
+                    };
+                }
+            }
+            Expr::Path(_) => {}
+            expr => self.visit_expr_mut(expr),
+        }
+    }
+
+    /// Visits `exprs`, whose values are moved or copied out.
+    fn values<'e>(&mut self, exprs: impl IntoIterator<Item = &'e mut Expr>) {
+        for expr in exprs {
+            self.child(expr, Context::Value);
+        }
+    }
+
+    /// Uses a macro argument which parses as an expression. Macros such as
+    /// `format!` and `assert_eq!` take their arguments by reference.
+    fn arg(&mut self, arg: &mut Expr) {
+        match arg {
+            Expr::Lit(ExprLit {
+                lit: Lit::Str(lit), ..
+            }) => self.format_args(lit),
+            // A named argument, as in `format!`.
+            Expr::Assign(assign) if matches!(&*assign.left, Expr::Path(_)) => {
+                self.child(&mut assign.right, Context::Place);
+            }
+            arg => self.child(arg, Context::Place),
+        }
+    }
+
+    /// Uses every identifier among `tokens` which could be a variable.
+    fn tokens(&mut self, tokens: TokenStream2) {
+        let tokens = tokens.into_iter().collect::<Vec<_>>();
+        let punct = |i: usize, ch: char| matches!(tokens.get(i), Some(TokenTree::Punct(p)) if p.as_char() == ch);
+        for (i, token) in tokens.iter().enumerate() {
+            match token {
+                TokenTree::Group(group) => self.tokens(group.stream()),
+                TokenTree::Literal(lit) => {
+                    if let Ok(lit) = syn::parse2::<LitStr>(lit.to_token_stream()) {
+                        self.format_args(&lit);
+                    }
+                }
+                // Not a field or method name, a path segment, or a macro.
+                TokenTree::Ident(ident)
+                    if !(i > 0 && (punct(i - 1, '.') || punct(i - 1, ':')))
+                        && !punct(i + 1, '!')
+                        && !punct(i + 1, ':') =>
+                {
+                    // Keywords don't parse as identifiers.
+                    if let Ok(ident) = syn::parse2::<Ident>(ident.to_token_stream()) {
+                        self.use_name(ident);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Uses the `{name}` arguments of a format string.
+    fn format_args(&mut self, lit: &LitStr) {
+        let value = lit.value();
+        let mut rest = value.as_str();
+        while let Some(i) = rest.find('{') {
+            rest = &rest[i + 1..];
+            if let Some(escaped) = rest.strip_prefix('{') {
+                rest = escaped;
+                continue;
+            }
+            let name = &rest[..rest.find([':', '}']).unwrap_or(rest.len())];
+            if let Ok(ident) = syn::parse_str::<Ident>(name) {
+                self.use_name(Ident::new(&ident.to_string(), lit.span()));
+            }
+        }
+    }
+}
+
+impl VisitMut for Names<'_> {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        use Context::{Auto, Place, Postfix, Value};
+        match expr {
+            Expr::Path(_) | Expr::Paren(_) | Expr::Group(_) => self.child(expr, Place),
+            Expr::Array(e) => self.values(&mut e.elems),
+            Expr::Assign(e) => {
+                self.mutate(&e.left);
+                self.child(&mut e.left, Place);
+                self.child(&mut e.right, Value);
+            }
+            Expr::Await(e) => self.child(&mut e.base, Postfix),
+            Expr::Binary(e) => {
+                let left = if is_assign(&e.op) {
+                    self.mutate(&e.left);
+                    Place
+                } else if is_comparison(&e.op) {
+                    Place
+                } else {
+                    Value
+                };
+                let right = if is_comparison(&e.op) { Place } else { Value };
+                self.child(&mut e.left, left);
+                self.child(&mut e.right, right);
+            }
+            Expr::Break(e) => self.values(e.expr.as_deref_mut()),
+            Expr::Call(e) => {
+                self.child(&mut e.func, Auto);
+                self.values(&mut e.args);
+            }
+            Expr::Cast(e) => self.child(&mut e.expr, Value),
+            Expr::Closure(e) => self.closure(e),
+            Expr::Field(e) => self.child(&mut e.base, Auto),
+            Expr::ForLoop(e) => {
+                self.child(&mut e.expr, Value);
+                self.scoped(|n| {
+                    n.bind(&e.pat);
+                    n.visit_block_mut(&mut e.body);
+                });
+            }
+            Expr::If(e) => {
+                self.scoped(|n| {
+                    n.child(&mut e.cond, Value);
+                    n.visit_block_mut(&mut e.then_branch);
+                });
+                if let Some((_, else_branch)) = &mut e.else_branch {
+                    self.child(else_branch, Value);
+                }
+            }
+            Expr::Index(e) => {
+                self.child(&mut e.expr, Auto);
+                self.child(&mut e.index, Value);
+            }
+            Expr::Let(e) => {
+                self.child(&mut e.expr, Auto);
+                self.bind(&e.pat);
+            }
+            Expr::Match(e) => {
+                self.child(&mut e.expr, Auto);
+                for arm in &mut e.arms {
+                    self.scoped(|n| {
+                        n.bind(&arm.pat);
+                        if let Some((_, guard)) = &mut arm.guard {
+                            n.child(guard, Value);
+                        }
+                        n.child(&mut arm.body, Value);
+                    });
+                }
+            }
+            Expr::MethodCall(e) => {
+                self.mutate(&e.receiver);
+                self.child(&mut e.receiver, Auto);
+                self.values(&mut e.args);
+            }
+            Expr::Range(e) => {
+                self.values(e.start.as_deref_mut());
+                self.values(e.end.as_deref_mut());
+            }
+            Expr::Reference(e) => {
+                if e.mutability.is_some() {
+                    self.mutate(&e.expr);
+                }
+                self.child(&mut e.expr, Place);
+            }
+            Expr::Repeat(e) => self.child(&mut e.expr, Value),
+            Expr::Return(e) => self.values(e.expr.as_deref_mut()),
+            Expr::Struct(e) => {
+                self.values(e.fields.iter_mut().map(|f| &mut f.expr));
+                self.values(e.rest.as_deref_mut());
+            }
+            Expr::Try(e) => self.child(&mut e.expr, Postfix),
+            Expr::Tuple(e) => self.values(&mut e.elems),
+            Expr::Unary(e) => {
+                let context = match e.op {
+                    syn::UnOp::Deref(_) => Place,
+                    _ => Value,
+                };
+                self.child(&mut e.expr, context);
+            }
+            Expr::While(e) => self.scoped(|n| {
+                n.child(&mut e.cond, Value);
+                n.visit_block_mut(&mut e.body);
+            }),
+            Expr::Yield(e) => self.values(e.expr.as_deref_mut()),
+            expr => syn::visit_mut::visit_expr_mut(self, expr),
+        }
+    }
+
+    fn visit_block_mut(&mut self, block: &mut Block) {
+        self.scoped(|n| {
+            for stmt in &mut block.stmts {
+                n.visit_stmt_mut(stmt);
+            }
+        });
+    }
+
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+        match stmt {
+            Stmt::Local(local) => {
+                if let Some(init) = &mut local.init {
+                    self.local_init(init);
+                }
+                self.bind(&local.pat);
+            }
+            // Items within the body can't refer to its variables.
+            Stmt::Item(_) => {}
+            Stmt::Expr(expr, _) => self.child(expr, Context::Value),
+            Stmt::Macro(stmt) => self.visit_macro_mut(&mut stmt.mac),
+        }
+    }
+
+    fn visit_macro_mut(&mut self, mac: &mut Macro) {
+        self.mention(mac.tokens.clone());
+        let done = self.done.len();
+        if let Some(mut args) = macro_args(mac) {
+            for arg in &mut args {
+                self.arg(arg);
+            }
+            if self.done.len() > done {
+                mac.tokens = args.into_token_stream();
+            }
+            return;
+        }
+        let mut tokens = Vec::new();
+        for arg in split_args(mac.tokens.clone()) {
+            if let Ok(mut expr) = syn::parse2::<Expr>(arg.clone()) {
+                self.arg(&mut expr);
+                tokens.push(expr.into_token_stream());
+            } else if let Ok((pat, mut guard)) = arm.parse2(arg.clone()) {
+                self.scoped(|n| {
+                    n.bind(&pat);
+                    if let Some(guard) = &mut guard {
+                        n.child(guard, Context::Value);
+                    }
+                });
+                tokens.push(arg);
+            } else {
+                self.tokens(arg.clone());
+                tokens.push(arg);
+            }
+        }
+        if self.done.len() > done {
+            let comma = <Token![,]>::default();
+            let mut joined = TokenStream2::new();
+            for (i, arg) in tokens.into_iter().enumerate() {
+                if i > 0 {
+                    comma.to_tokens(&mut joined);
+                }
+                joined.extend(arg);
+            }
+            mac.tokens = joined;
+        }
+    }
+}
+
+/// The variable whose value, or part of it, `expr` names.
+fn place(expr: &Expr) -> Option<&Ident> {
+    match expr {
+        Expr::Path(path) if path.qself.is_none() => path.path.get_ident(),
+        Expr::Field(field) => place(&field.base),
+        Expr::Index(index) => place(&index.expr),
+        Expr::Paren(paren) => place(&paren.expr),
+        _ => None,
+    }
+}
+
+fn is_comparison(op: &BinOp) -> bool {
+    matches!(
+        op,
+        BinOp::Eq(_) | BinOp::Ne(_) | BinOp::Lt(_) | BinOp::Le(_) | BinOp::Gt(_) | BinOp::Ge(_)
+    )
+}
+
+fn is_assign(op: &BinOp) -> bool {
+    matches!(
+        op,
+        BinOp::AddAssign(_)
+            | BinOp::SubAssign(_)
+            | BinOp::MulAssign(_)
+            | BinOp::DivAssign(_)
+            | BinOp::RemAssign(_)
+            | BinOp::BitXorAssign(_)
+            | BinOp::BitAndAssign(_)
+            | BinOp::BitOrAssign(_)
+            | BinOp::ShlAssign(_)
+            | BinOp::ShrAssign(_)
+    )
+}
+
+/// Splits a macro's tokens at top-level commas.
+fn split_args(tokens: TokenStream2) -> Vec<TokenStream2> {
+    let mut args = vec![TokenStream2::new()];
+    for token in tokens {
+        match &token {
+            TokenTree::Punct(p) if p.as_char() == ',' => args.push(TokenStream2::new()),
+            _ => args.last_mut().unwrap().extend([token]),
+        }
+    }
+    args
+}
+
+/// A pattern with an optional guard, as in the second argument of
+/// `matches!`.
+fn arm(input: ParseStream) -> syn::Result<(Pat, Option<Expr>)> {
+    let pat = Pat::parse_multi_with_leading_vert(input)?;
+    let guard = if input.parse::<Option<Token![if]>>()?.is_some() {
+        Some(input.parse()?)
+    } else {
+        None
+    };
+    Ok((pat, guard))
+}
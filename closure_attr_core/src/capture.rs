@@ -0,0 +1,581 @@
+//! The capture data model and the `#[closure(...)]` syntax which produces it.
+
+use proc_macro2::{Span, TokenStream as TokenStream2, TokenTree};
+use quote::{quote, ToTokens};
+use syn::{
+    ext::IdentExt,
+    parenthesized,
+    parse::discouraged::Speculative,
+    parse::{Parse, ParseStream},
+    punctuated::Punctuated,
+    spanned::Spanned,
+    visit_mut::VisitMut,
+    Error, Expr, ExprPath, Ident, Pat, PatIdent, Token, Type, TypeParamBound,
+};
+
+use crate::{glib::Glib, report};
+
+/// How a capture binds its variable. Each variant corresponds to one of the
+/// capture types accepted by `#[closure(...)]`.
+#[derive(Clone)]
+#[non_exhaustive]
+pub enum CaptureKind {
+    /// `clone`
+    Clone,
+    /// `clone mut`
+    CloneMut,
+    /// `ref`
+    Ref,
+    /// `ref mut`
+    RefMut,
+    /// `move`
+    Move,
+    /// `move mut`
+    MoveMut,
+    /// `weak`
+    Weak,
+    /// `upgrade`
+    Upgrade,
+    /// `fail(<expr>)`
+    Fail(Box<Expr>),
+    /// `panic`
+    Panic,
+    /// `enter(<expr>)`
+    Enter(Box<Expr>),
+    /// `enter(<expr>) mut`
+    EnterMut(Box<Expr>),
+    /// `using(<type>)` or `using(<type>, <expr>)`
+    Using(Box<Type>, Option<Box<Expr>>),
+    /// `using(<type>) mut` or `using(<type>, <expr>) mut`
+    UsingMut(Box<Type>, Option<Box<Expr>>),
+    /// `to_owned`
+    ToOwned,
+    /// `into`, optionally with the target type
+    Into(Option<Box<Type>>),
+    /// `via(<path>)`
+    Via(Box<ExprPath>),
+    /// `try_clone(<expr>)`, or `try_clone(?)` when `None`
+    TryClone(Option<Box<Expr>>),
+    /// `try_clone(<expr>) mut`, or `try_clone(?) mut` when `None`
+    TryCloneMut(Option<Box<Expr>>),
+    /// `share`
+    Share,
+}
+
+/// What a capture binds: either a variable, or a pattern which destructures
+/// an expression.
+#[derive(Clone)]
+#[non_exhaustive]
+pub enum CaptureTarget {
+    /// `<ident>`
+    Ident(Ident),
+    /// `<pat> = <expr>`
+    Pat(Box<Pat>, Box<Expr>),
+}
+
+/// A single capture, e.g. `clone a` or `weak (a, b) = pair`.
+///
+/// Parse one from `#[closure(...)]` syntax with [syn::parse2], build one with
+/// [CaptureSpec::new] or [CaptureSpec::destructure], and print one back with
+/// [ToTokens].
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct CaptureSpec {
+    pub kind: CaptureKind,
+    pub target: CaptureTarget,
+    /// Where the capture type is written. The code which the capture expands
+    /// to uses it, so errors in that code point at the capture.
+    pub span: Span,
+}
+
+impl CaptureSpec {
+    /// A capture of the variable `ident`, spanned by `ident`.
+    pub fn new(kind: CaptureKind, ident: Ident) -> Self {
+        CaptureSpec {
+            kind,
+            span: ident.span(),
+            target: CaptureTarget::Ident(ident),
+        }
+    }
+
+    /// A capture which binds `pat` to `expr`, spanned by `pat`.
+    pub fn destructure(kind: CaptureKind, pat: Pat, expr: Expr) -> Self {
+        CaptureSpec {
+            kind,
+            span: pat.span(),
+            target: CaptureTarget::Pat(Box::new(pat), Box::new(expr)),
+        }
+    }
+
+    /// The identifiers the capture binds within the closure.
+    pub fn idents(&self) -> Vec<Ident> {
+        self.target.idents()
+    }
+}
+
+impl Parse for CaptureSpec {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let span = input.span();
+        let ty = match Ident::parse_any(input) {
+            Ok(v) => v,
+            Err(e) => Err(Error::new(
+                e.span(),
+                // The (1) and (2) tags aid testing and debugging.
+                "expected clone, clone mut, ref, ref mut, move, move mut, weak, upgrade, fail, panic, enter, using, to_owned, into, via, try_clone, or share (1)",
+            ))?,
+        };
+        let mut ty = ty.to_string();
+        let mut expr = None;
+        let mut kind = None;
+        let mut path = None;
+        if ty == "via" {
+            let paren;
+            parenthesized!(paren in input);
+            path = Some(Box::new(paren.parse::<ExprPath>()?));
+            if !paren.is_empty() {
+                Err(Error::new(paren.span(), "expected end of path"))?;
+            }
+        } else if ty == "using" {
+            let paren;
+            parenthesized!(paren in input);
+            kind = Some(Box::new(paren.parse::<Type>()?));
+            if paren.peek(Token![,]) {
+                paren.parse::<Token![,]>()?;
+                expr = Some(Box::new(paren.parse::<Expr>()?));
+            }
+            if !paren.is_empty() {
+                Err(Error::new(paren.span(), "expected end of expression"))?;
+            }
+        } else if ty == "try_clone" {
+            let paren;
+            parenthesized!(paren in input);
+            if paren.peek(Token![?]) {
+                paren.parse::<Token![?]>()?;
+            } else {
+                expr = Some(Box::new(paren.parse::<Expr>()?));
+            }
+            if !paren.is_empty() {
+                Err(Error::new(paren.span(), "expected end of expression"))?;
+            }
+        } else if ty == "fail" || ty == "enter" {
+            let paren;
+            parenthesized!(paren in input);
+            expr = Some(Box::new(paren.parse::<Expr>()?));
+            if !paren.is_empty() {
+                Err(Error::new(paren.span(), "expected end of expression"))?;
+            }
+        }
+        if input.lookahead1().peek(Token![mut]) {
+            input.parse::<Token![mut]>()?;
+            ty += " mut";
+        }
+        let kind = match ty.as_str() {
+            "clone" => CaptureKind::Clone,
+            "clone mut" => CaptureKind::CloneMut,
+            "ref" => CaptureKind::Ref,
+            "ref mut" => CaptureKind::RefMut,
+            "move" => CaptureKind::Move,
+            "move mut" => CaptureKind::MoveMut,
+            "weak" => CaptureKind::Weak,
+            "upgrade" => CaptureKind::Upgrade,
+            "fail" => CaptureKind::Fail(expr.unwrap()),
+            "panic" => CaptureKind::Panic,
+            "enter" => CaptureKind::Enter(expr.unwrap()),
+            "enter mut" => CaptureKind::EnterMut(expr.unwrap()),
+            "using" => CaptureKind::Using(kind.unwrap(), expr),
+            "using mut" => CaptureKind::UsingMut(kind.unwrap(), expr),
+            "to_owned" => CaptureKind::ToOwned,
+            "into" => CaptureKind::Into(None),
+            "via" => CaptureKind::Via(path.unwrap()),
+            "try_clone" => CaptureKind::TryClone(expr),
+            "try_clone mut" => CaptureKind::TryCloneMut(expr),
+            "share" => CaptureKind::Share,
+            _ => {
+                let mut e = Error::new(
+                    span,
+                    "expected clone, clone mut, ref, ref mut, move, move mut, weak, upgrade, fail, panic, enter, using, to_owned, into, via, try_clone, or share (2)",
+                );
+                if let Some(hint) = hint(&ty, input) {
+                    e.combine(Error::new(span, hint));
+                }
+                Err(e)?
+            }
+        };
+        CaptureTarget::parse(input, kind, span)
+    }
+}
+
+/// The capture types, as written.
+const KINDS: &[&str] = &[
+    "clone",
+    "clone mut",
+    "ref",
+    "ref mut",
+    "move",
+    "move mut",
+    "weak",
+    "upgrade",
+    "fail",
+    "panic",
+    "enter",
+    "enter mut",
+    "using",
+    "using mut",
+    "to_owned",
+    "into",
+    "via",
+    "try_clone",
+    "try_clone mut",
+    "share",
+];
+
+/// A hint for `ty`, an unknown capture type: either the capture type written
+/// in the wrong order, or the closest one by spelling.
+fn hint(ty: &str, input: ParseStream) -> Option<String> {
+    if ty == "mut" {
+        let next = input.fork().call(Ident::parse_any).ok()?.to_string();
+        return match next.as_str() {
+            "clone" | "ref" | "move" => Some(format!("write `{next} mut`, not `mut {next}`")),
+            "enter" | "using" | "try_clone" => {
+                Some(format!("write `{next}(...) mut`, not `mut {next}(...)`"))
+            }
+            _ => None,
+        };
+    }
+    did_you_mean(ty, KINDS).map(|kind| format!("did you mean `{kind}`?"))
+}
+
+/// The candidate closest to `word` by edit distance, if it's close enough to
+/// be a likely typo.
+pub(crate) fn did_you_mean<'a>(word: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    let max = (word.chars().count() / 3).max(1);
+    candidates
+        .iter()
+        .map(|c| (edit_distance(word, c), *c))
+        .filter(|&(d, _)| d <= max)
+        .min_by_key(|&(d, _)| d)
+        .map(|(_, c)| c)
+}
+
+/// The Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let next = (prev + usize::from(ca != cb))
+                .min(row[j] + 1)
+                .min(row[j + 1] + 1);
+            prev = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+impl CaptureSpec {
+    /// The capture which re-applies this one to `ident`, one of its bindings,
+    /// within a nested closure, spanned by `span`. `None` if it can't be
+    /// re-applied.
+    pub(crate) fn inherit(&self, ident: Ident, span: Span) -> Option<CaptureSpec> {
+        let kind = match &self.kind {
+            CaptureKind::Clone | CaptureKind::Weak | CaptureKind::Into(_) | CaptureKind::Via(_) => {
+                CaptureKind::Clone
+            }
+            CaptureKind::CloneMut => CaptureKind::CloneMut,
+            CaptureKind::Ref | CaptureKind::Move => CaptureKind::Move,
+            CaptureKind::MoveMut => CaptureKind::MoveMut,
+            CaptureKind::Fail(expr) => CaptureKind::Fail(expr.clone()),
+            CaptureKind::Panic => CaptureKind::Panic,
+            CaptureKind::ToOwned => CaptureKind::ToOwned,
+            CaptureKind::Share => CaptureKind::Share,
+            CaptureKind::RefMut => {
+                // Reborrow the outer reference.
+                let pat = Pat::Ident(PatIdent {
+                    attrs: Vec::new(),
+                    by_ref: None,
+                    mutability: None,
+                    ident: ident.clone(),
+                    subpat: None,
+                });
+                let expr = syn::parse_quote!(*#ident);
+                return Some(CaptureSpec {
+                    kind: CaptureKind::RefMut,
+                    target: CaptureTarget::Pat(Box::new(pat), Box::new(expr)),
+                    span,
+                });
+            }
+            // Within the outer closure, the fail expression or `?` would
+            // return from it rather than from the enclosing function.
+            CaptureKind::TryClone(_) | CaptureKind::TryCloneMut(_) => return None,
+            // Cloning the upgraded `Option` would keep the pointer strong.
+            CaptureKind::Upgrade => return None,
+            CaptureKind::Enter(_)
+            | CaptureKind::EnterMut(_)
+            | CaptureKind::Using(..)
+            | CaptureKind::UsingMut(..) => return None,
+        };
+        Some(CaptureSpec {
+            kind,
+            target: CaptureTarget::Ident(ident),
+            span,
+        })
+    }
+}
+
+impl CaptureTarget {
+    /// Parses `<ident>` or `<pat> = <expr>`. `into` also accepts a type
+    /// before the `=`, or after the ident.
+    fn parse(input: ParseStream, mut kind: CaptureKind, span: Span) -> syn::Result<CaptureSpec> {
+        let fork = input.fork();
+        if let Ok(pat) = Pat::parse_single(&fork) {
+            let ty = parse_into_type(&fork, &kind)?;
+            if fork.peek(Token![=]) {
+                input.advance_to(&fork);
+                input.parse::<Token![=]>()?;
+                let expr = input.parse::<Expr>()?;
+                if let CaptureKind::Into(t) = &mut kind {
+                    *t = ty;
+                }
+                return Ok(CaptureSpec {
+                    kind,
+                    target: CaptureTarget::Pat(Box::new(pat), Box::new(expr)),
+                    span,
+                });
+            }
+        }
+        let ident = Ident::parse(input)?;
+        let ty = parse_into_type(input, &kind)?;
+        if let CaptureKind::Into(t) = &mut kind {
+            *t = ty;
+        }
+        Ok(CaptureSpec {
+            kind,
+            target: CaptureTarget::Ident(ident),
+            span,
+        })
+    }
+
+    /// The identifiers bound by the capture.
+    pub(crate) fn idents(&self) -> Vec<Ident> {
+        match self {
+            CaptureTarget::Ident(ident) => vec![ident.clone()],
+            CaptureTarget::Pat(pat, _) => {
+                let mut bindings = Bindings::default();
+                bindings.visit_pat_mut(&mut pat.clone());
+                bindings.idents
+            }
+        }
+    }
+}
+
+impl ToTokens for CaptureSpec {
+    fn to_tokens(&self, tokens: &mut TokenStream2) {
+        let kind = match &self.kind {
+            CaptureKind::Clone => quote! {clone},
+            CaptureKind::CloneMut => quote! {clone mut},
+            CaptureKind::Ref => quote! {ref},
+            CaptureKind::RefMut => quote! {ref mut},
+            CaptureKind::Move => quote! {move},
+            CaptureKind::MoveMut => quote! {move mut},
+            CaptureKind::Weak => quote! {weak},
+            CaptureKind::Upgrade => quote! {upgrade},
+            CaptureKind::Fail(expr) => quote! {fail(#expr)},
+            CaptureKind::Panic => quote! {panic},
+            CaptureKind::Enter(expr) => quote! {enter(#expr)},
+            CaptureKind::EnterMut(expr) => quote! {enter(#expr) mut},
+            CaptureKind::Using(ty, None) => quote! {using(#ty)},
+            CaptureKind::Using(ty, Some(expr)) => quote! {using(#ty, #expr)},
+            CaptureKind::UsingMut(ty, None) => quote! {using(#ty) mut},
+            CaptureKind::UsingMut(ty, Some(expr)) => quote! {using(#ty, #expr) mut},
+            CaptureKind::ToOwned => quote! {to_owned},
+            CaptureKind::Into(_) => quote! {into},
+            CaptureKind::Via(path) => quote! {via(#path)},
+            CaptureKind::TryClone(None) => quote! {try_clone(?)},
+            CaptureKind::TryClone(Some(expr)) => quote! {try_clone(#expr)},
+            CaptureKind::TryCloneMut(None) => quote! {try_clone(?) mut},
+            CaptureKind::TryCloneMut(Some(expr)) => quote! {try_clone(#expr) mut},
+            CaptureKind::Share => quote! {share},
+        };
+        let ty = match &self.kind {
+            CaptureKind::Into(Some(ty)) => Some(quote! {: #ty}),
+            _ => None,
+        };
+        tokens.extend(match &self.target {
+            CaptureTarget::Ident(ident) => quote! {#kind #ident #ty},
+            CaptureTarget::Pat(pat, expr) => quote! {#kind #pat #ty = #expr},
+        });
+    }
+}
+
+fn parse_into_type(input: ParseStream, kind: &CaptureKind) -> syn::Result<Option<Box<Type>>> {
+    if matches!(kind, CaptureKind::Into(_)) && input.peek(Token![:]) {
+        input.parse::<Token![:]>()?;
+        return Ok(Some(Box::new(input.parse::<Type>()?)));
+    }
+    Ok(None)
+}
+
+/// Collects the identifiers bound by a pattern, optionally making them `mut`.
+#[derive(Default)]
+pub(crate) struct Bindings {
+    pub(crate) make_mut: bool,
+    pub(crate) idents: Vec<Ident>,
+}
+
+impl VisitMut for Bindings {
+    fn visit_pat_ident_mut(&mut self, pat: &mut PatIdent) {
+        if self.make_mut && pat.by_ref.is_none() {
+            pat.mutability = Some(Default::default());
+        }
+        self.idents.push(pat.ident.clone());
+        syn::visit_mut::visit_pat_ident_mut(self, pat);
+    }
+}
+
+pub(crate) enum Entry {
+    Capture(CaptureSpec),
+    /// `use <name>` includes a capture set declared on `with_closure`.
+    Use(Ident),
+    /// `inherit` or `inherit(except <idents>)` re-applies the captures of the
+    /// enclosing closure.
+    Inherit(Ident, Vec<Ident>),
+    Assert(Assert),
+}
+
+/// `assert(<bounds>)`, e.g. `assert(Send + 'static + Fn())`: bounds which
+/// the closure must satisfy, checked where it's written.
+#[derive(Clone)]
+pub(crate) struct Assert {
+    pub(crate) span: Span,
+    pub(crate) bounds: Punctuated<TypeParamBound, Token![+]>,
+}
+
+impl Parse for Entry {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.peek(Token![use]) {
+            input.parse::<Token![use]>()?;
+            return Ok(Entry::Use(Ident::parse(input)?));
+        }
+        if input
+            .fork()
+            .call(Ident::parse_any)
+            .is_ok_and(|i| i == "inherit")
+        {
+            let inherit = Ident::parse_any(input)?;
+            let mut except = Vec::new();
+            if input.peek(syn::token::Paren) {
+                let paren;
+                parenthesized!(paren in input);
+                let kw = Ident::parse_any(&paren)?;
+                if kw != "except" {
+                    Err(Error::new(kw.span(), "expected except"))?;
+                }
+                except.extend(paren.parse_terminated(Ident::parse, Token![,])?);
+            }
+            return Ok(Entry::Inherit(inherit, except));
+        }
+        if input.peek2(syn::token::Paren)
+            && input
+                .fork()
+                .call(Ident::parse_any)
+                .is_ok_and(|i| i == "assert")
+        {
+            let span = Ident::parse_any(input)?.span();
+            let paren;
+            parenthesized!(paren in input);
+            let bounds = Punctuated::parse_separated_nonempty(&paren)?;
+            if !paren.is_empty() {
+                Err(paren.error("expected `+`"))?;
+            }
+            return Ok(Entry::Assert(Assert { span, bounds }));
+        }
+        Ok(Entry::Capture(CaptureSpec::parse(input)?))
+    }
+}
+
+/// A capture list. Parsing recovers at each comma: malformed entries are
+/// skipped and their errors collected, so the valid ones can still be
+/// expanded.
+pub(crate) struct Captures {
+    pub(crate) entries: Vec<Entry>,
+    pub(crate) errors: Option<Error>,
+}
+
+impl Parse for Captures {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut errors: Option<Error> = None;
+        let mut parts = Vec::new();
+        while !input.is_empty() {
+            // Parse on a fork so that an entry which fails within parentheses
+            // doesn't leave their remaining tokens unconsumed.
+            let fork = input.fork();
+            let part = if fork.peek(Token![@]) {
+                fork.parse().map(Part::Glib)
+            } else {
+                fork.parse().map(Part::Entry)
+            };
+            match part {
+                Ok(part) => {
+                    input.advance_to(&fork);
+                    parts.push(part);
+                }
+                Err(e) => {
+                    report(&mut errors, e);
+                    skip_entry(input);
+                    continue;
+                }
+            }
+            if !input.is_empty() {
+                if let Err(e) = input.parse::<Token![,]>() {
+                    report(&mut errors, e);
+                    skip_entry(input);
+                }
+            }
+        }
+
+        // `@default-return` applies to every `@weak` in the list.
+        let mut default_return = None;
+        for part in &parts {
+            if let Part::Glib(Glib::DefaultReturn(span, expr)) = part {
+                if default_return.is_some() {
+                    report(&mut errors, Error::new(*span, "duplicate @default-return"));
+                }
+                default_return = Some(expr.clone());
+            }
+        }
+        let default_return = default_return.unwrap_or_else(|| syn::parse_quote!(()));
+        let entries = parts
+            .into_iter()
+            .filter_map(|part| match part {
+                Part::Entry(entry) => Some(entry),
+                Part::Glib(Glib::Capture(capture)) => Some(Entry::Capture(capture)),
+                Part::Glib(Glib::Weak(span, target)) => Some(Entry::Capture(CaptureSpec {
+                    kind: CaptureKind::Fail(default_return.clone()),
+                    target,
+                    span,
+                })),
+                Part::Glib(Glib::DefaultReturn(..)) => None,
+            })
+            .collect();
+        Ok(Captures { entries, errors })
+    }
+}
+
+/// An entry of a capture list, or a glib-style capture within it.
+enum Part {
+    Entry(Entry),
+    Glib(Glib),
+}
+
+/// Skips the rest of a malformed entry, up to and including the next comma.
+pub(crate) fn skip_entry(input: ParseStream) {
+    while !input.is_empty() {
+        if input.parse::<Token![,]>().is_ok() {
+            return;
+        }
+        let _ = input.parse::<TokenTree>();
+    }
+}
@@ -0,0 +1,374 @@
+//! Rewriting `#[closure]` closures into plain Rust source.
+
+use std::collections::BTreeMap;
+
+use proc_macro2::{LineColumn, Span, TokenStream as TokenStream2};
+use syn::{spanned::Spanned, visit::Visit, visit_mut::VisitMut, Attribute, Expr, Meta};
+
+use crate::{
+    source::{is_with_closure, Text},
+    visitor::{ClosureVisitor, Ejection},
+};
+
+/// Rewrites the source file `src` so it doesn't need `closure_attr_derive`.
+///
+/// Each `#[closure(...)]` closure within a `#[with_closure]` item becomes a
+/// block which binds its captures, like the expansion, but formatted for
+/// people to read. `#[with_closure]` attributes and
+/// `use closure_attr::with_closure;` are removed. Everything else, including
+/// comments and formatting within closure bodies, is kept.
+///
+/// The result still refers to `closure_attr` for captures such as `weak`,
+/// which use its traits.
+pub fn eject(src: &str) -> syn::Result<String> {
+    let file = syn::parse_file(src)?;
+    let mut items = Items {
+        ejections: Vec::new(),
+        removed: Vec::new(),
+        errors: None,
+    };
+    items.visit_file(&file);
+    if let Some(e) = items.errors {
+        return Err(e);
+    }
+
+    let text = Text::new(src);
+    let mut edits = Vec::new();
+    for range in items.removed {
+        let (start, end) = text.lines(range);
+        edits.push(Edit::new(start, end, LAST, String::new()));
+    }
+    let depths = items
+        .ejections
+        .iter()
+        .map(|e| {
+            let start = text.offset(e.start);
+            items
+                .ejections
+                .iter()
+                .filter(|o| text.offset(o.closure.0) < start && start < text.offset(o.closure.1))
+                .count() as i64
+        })
+        .collect::<Vec<_>>();
+
+    // Closures lose the indentation of the blocks which wrap them.
+    let block = |e: &Ejection| !e.locals.is_empty() || !e.checks.is_empty();
+    let mut shifts = BTreeMap::<usize, usize>::new();
+    for e in &items.ejections {
+        if block(e) {
+            for line in e.closure.0.line + 1..=e.closure.1.line {
+                *shifts.entry(line).or_default() += 4;
+            }
+        }
+    }
+    let indent = |line: usize| text.indent(line) + shifts.get(&line).copied().unwrap_or(0);
+
+    // Where text which was on the same line as something before it now
+    // starts a line: its offset, original line, and new indentation. A
+    // closure nested on the same line as its enclosing closure is indented
+    // from there.
+    let mut moved = Vec::<(usize, usize, usize)>::new();
+    let mut order = (0..items.ejections.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| depths[i]);
+    for i in order {
+        let (e, depth) = (&items.ejections[i], depths[i]);
+        for &(start, end) in &e.attrs {
+            let (start, end) = (text.offset(start), text.offset(end));
+            let end = end + (src[end..].len() - src[end..].trim_start().len());
+            edits.push(Edit::new(start, end, LAST, String::new()));
+        }
+
+        if let Some(pos) = e.add_move {
+            let pos = text.offset(pos);
+            edits.push(Edit::new(
+                pos,
+                pos,
+                opening(depth, CLOSURE),
+                "move ".to_string(),
+            ));
+        }
+        for deref in &e.derefs {
+            let start = text.offset(deref.span.start());
+            let open = if deref.parens { "(*" } else { "*" };
+            edits.push(Edit::new(start, start, INNERMOST, open.to_string()));
+            if deref.parens {
+                let end = text.offset(deref.span.end());
+                edits.push(Edit::new(end, end, -INNERMOST, ")".to_string()));
+            }
+        }
+
+        let start = text.offset(e.start);
+        let mut ind = moved
+            .iter()
+            .filter(|m| m.1 == e.start.line && m.0 <= start)
+            .max_by_key(|m| m.0)
+            .map_or_else(|| indent(e.start.line), |m| m.2);
+        if block(e) {
+            let mut open = String::from("{\n");
+            for line in format(&e.locals) {
+                open += &format!("{:ind$}{line}\n", "", ind = ind + 4);
+            }
+            open += &" ".repeat(ind + 4);
+            edits.push(Edit::new(start, start, opening(depth, BLOCK), open));
+            let end = text.offset(e.closure.1);
+            edits.push(Edit::new(
+                end,
+                end,
+                closing(depth, BLOCK),
+                format!("\n{:ind$}}}", ""),
+            ));
+            ind += 4;
+            moved.push((text.offset(e.closure.0), e.start.line, ind));
+        }
+        if e.assert || !e.checks.is_empty() {
+            let (mut open, mut close) = (String::new(), String::new());
+            if !e.checks.is_empty() {
+                open += "let closure = ";
+            }
+            if e.assert {
+                open += "assert_closure(";
+                close += ")";
+            }
+            if !e.checks.is_empty() {
+                close += ";\n";
+                for line in format(&e.checks) {
+                    close += &format!("{:ind$}{line}\n", "");
+                }
+                close += &format!("{:ind$}closure", "");
+            }
+            let (start, end) = (text.offset(e.closure.0), text.offset(e.closure.1));
+            edits.push(Edit::new(start, start, opening(depth, CALL), open));
+            edits.push(Edit::new(end, end, closing(depth, CALL), close));
+        }
+        if e.prelude.is_empty() {
+            continue;
+        }
+
+        let prelude = format(&e.prelude);
+        let lines = |ind: usize| {
+            prelude
+                .iter()
+                .map(|line| format!("{:ind$}{line}\n", ""))
+                .collect::<String>()
+        };
+        let body_line = e.body.span().start().line;
+        let body_ind = if body_line == e.closure.0.line {
+            ind
+        } else {
+            indent(body_line)
+        };
+        let (open, close) = (opening(depth, CLOSURE), closing(depth, CLOSURE));
+        match &*e.body {
+            Expr::Block(block) if block.attrs.is_empty() && block.label.is_none() => {
+                let braces = &block.block.brace_token.span;
+                let start = text.offset(braces.open().end());
+                let end = text.offset(braces.close().start());
+                let stmts = &block.block.stmts;
+                match (stmts.first(), stmts.last()) {
+                    // Already multi-line: add the prelude before the first
+                    // statement, at its indentation.
+                    (Some(first), _) if first.span().start().line > braces.open().end().line => {
+                        let ind = indent(first.span().start().line);
+                        let text = prelude
+                            .iter()
+                            .map(|line| format!("\n{:ind$}{line}", ""))
+                            .collect();
+                        edits.push(Edit::new(start, start, open, text));
+                    }
+                    (Some(first), Some(last)) => {
+                        let first = text.offset(first.span().start());
+                        let last = text.offset(last.span().end());
+                        let text =
+                            format!("\n{}{:ind$}", lines(body_ind + 4), "", ind = body_ind + 4);
+                        edits.push(Edit::new(start, first, open, text));
+                        edits.push(Edit::new(last, end, close, format!("\n{:body_ind$}", "")));
+                        moved.push((first, body_line, body_ind + 4));
+                    }
+                    _ => {
+                        let text = format!("\n{}{:body_ind$}", lines(body_ind + 4), "");
+                        edits.push(Edit::new(start, end, open, text));
+                    }
+                }
+            }
+            body => {
+                // A closure attribute stripped from the body precedes its span.
+                let start = items
+                    .ejections
+                    .iter()
+                    .find(|o| o.closure.0 == body.span().start())
+                    .map_or(body.span().start(), |o| o.start);
+                let start = text.offset(start);
+                let end = text.offset(body.span().end());
+                let text = format!("{{\n{}{:ind$}", lines(body_ind + 4), "", ind = body_ind + 4);
+                edits.push(Edit::new(start, start, open, text));
+                moved.push((start, body_line, body_ind + 4));
+                edits.push(Edit::new(end, end, close, format!("\n{:body_ind$}}}", "")));
+            }
+        }
+    }
+
+    for (line, shift) in shifts {
+        let start = text.line_start(line);
+        let blank = src[start..].trim_start_matches([' ', '\t']);
+        if blank.is_empty() || blank.starts_with('\n') {
+            continue;
+        }
+        if edits.iter().any(|e| e.start < start && start < e.end) {
+            continue;
+        }
+        edits.push(Edit::new(start, start, FIRST, " ".repeat(shift)));
+    }
+
+    edits.sort_by_key(|e| (e.start, e.rank));
+    let mut out = String::new();
+    let mut pos = 0;
+    for edit in edits {
+        if edit.start < pos {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "can't eject: rewrites of nested closures overlap",
+            ));
+        }
+        out.push_str(&src[pos..edit.start]);
+        out.push_str(&edit.text);
+        pos = edit.end;
+    }
+    out.push_str(&src[pos..]);
+    Ok(out)
+}
+
+// Ranks order edits at the same position. Indentation added at the start of
+// a line comes first, and removals last, after insertions at their start.
+// A closure's opening text goes after that of the closures enclosing it, and
+// its closing text before theirs. Within a closure, the block which binds its
+// captures encloses the `assert_closure(...)` call, which encloses the
+// closure's own edits. A dereference added to a variable in a body is within
+// all of them.
+const FIRST: i64 = i64::MIN;
+const LAST: i64 = i64::MAX;
+const INNERMOST: i64 = i64::MAX - 1;
+const BLOCK: i64 = 0;
+const CALL: i64 = 1;
+const CLOSURE: i64 = 2;
+
+/// The rank of text which opens `part` of a closure nested `depth` deep.
+fn opening(depth: i64, part: i64) -> i64 {
+    depth * 3 + part
+}
+
+/// The rank of text which closes `part` of a closure nested `depth` deep.
+fn closing(depth: i64, part: i64) -> i64 {
+    -(depth * 3 + part)
+}
+
+/// Replaces `start..end` with `text`. Edits at the same position apply in
+/// order of `rank`.
+struct Edit {
+    start: usize,
+    end: usize,
+    rank: i64,
+    text: String,
+}
+
+impl Edit {
+    fn new(start: usize, end: usize, rank: i64, text: String) -> Self {
+        Edit {
+            start,
+            end,
+            rank,
+            text,
+        }
+    }
+}
+
+/// Formats statements, one line per element.
+fn format(stmts: &TokenStream2) -> Vec<String> {
+    if stmts.is_empty() {
+        return Vec::new();
+    }
+    let file: syn::File = syn::parse_quote!(fn f() { #stmts });
+    let text = prettyplease::unparse(&file);
+    let lines = text.lines().collect::<Vec<_>>();
+    lines[1..lines.len() - 1]
+        .iter()
+        .map(|l| l.strip_prefix("    ").unwrap_or(l).to_string())
+        .collect()
+}
+
+/// Finds the `#[with_closure]` items and collects their ejections.
+struct Items {
+    ejections: Vec<Ejection>,
+    /// Ranges of source to remove.
+    removed: Vec<(LineColumn, LineColumn)>,
+    errors: Option<syn::Error>,
+}
+
+impl Items {
+    fn report(&mut self, e: syn::Error) {
+        crate::report(&mut self.errors, e);
+    }
+
+    /// Ejects the closures in `node` if `attrs` holds `#[with_closure]`.
+    fn item<T: Clone>(
+        &mut self,
+        attrs: &[Attribute],
+        node: &T,
+        visit: impl FnOnce(&mut ClosureVisitor, &mut T),
+    ) -> bool {
+        let Some(attr) = attrs.iter().find(|a| is_with_closure(a)) else {
+            return false;
+        };
+        self.removed.push((attr.span().start(), attr.span().end()));
+        let args = match &attr.meta {
+            Meta::List(list) => list.tokens.clone(),
+            _ => TokenStream2::new(),
+        };
+        let mut visitor = match ClosureVisitor::with_args(args) {
+            Ok(visitor) => visitor,
+            Err(e) => {
+                self.report(e);
+                return true;
+            }
+        };
+        visitor.start_ejecting();
+        visit(&mut visitor, &mut node.clone());
+        if let Some(e) = visitor.take_errors() {
+            self.report(e);
+        }
+        self.ejections.extend(visitor.ejected.take().unwrap());
+        true
+    }
+}
+
+impl<'ast> Visit<'ast> for Items {
+    fn visit_item(&mut self, item: &'ast syn::Item) {
+        if let syn::Item::Use(u) = item {
+            let tree = &u.tree;
+            let path = quote::quote!(#tree).to_string();
+            if path == "closure_attr :: with_closure" {
+                self.removed.push((u.span().start(), u.span().end()));
+            }
+            return;
+        }
+        let attrs = match item {
+            syn::Item::Const(i) => &i.attrs[..],
+            syn::Item::Enum(i) => &i.attrs,
+            syn::Item::Fn(i) => &i.attrs,
+            syn::Item::Impl(i) => &i.attrs,
+            syn::Item::Mod(i) => &i.attrs,
+            syn::Item::Static(i) => &i.attrs,
+            syn::Item::Struct(i) => &i.attrs,
+            syn::Item::Trait(i) => &i.attrs,
+            _ => &[],
+        };
+        if !self.item(attrs, item, |v, item| v.visit_item_mut(item)) {
+            syn::visit::visit_item(self, item);
+        }
+    }
+
+    fn visit_impl_item_fn(&mut self, f: &'ast syn::ImplItemFn) {
+        if !self.item(&f.attrs, f, |v, f| v.visit_impl_item_fn_mut(f)) {
+            syn::visit::visit_impl_item_fn(self, f);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! The `glib::clone!` capture syntax from gtk-rs, e.g. `@weak a` or
+//! `@strong self.b as b`, for teams migrating from it.
+
+use proc_macro2::Span;
+use syn::{
+    ext::IdentExt,
+    parse::{Parse, ParseStream},
+    spanned::Spanned,
+    Error, Expr, Ident, Member, Pat, PatIdent, Token,
+};
+
+use crate::capture::{did_you_mean, CaptureKind, CaptureSpec, CaptureTarget};
+
+/// A single `@`-prefixed entry.
+pub(crate) enum Glib {
+    /// `@strong`, `@to-owned`, or `@weak-allow-none`, which map directly to
+    /// `clone`, `to_owned`, or `upgrade`.
+    Capture(CaptureSpec),
+    /// `@weak`, which maps to `fail(<default-return>)`.
+    Weak(Span, CaptureTarget),
+    /// `@default-return <expr>`.
+    DefaultReturn(Span, Box<Expr>),
+}
+
+impl Parse for Glib {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let at = input.parse::<Token![@]>()?;
+        let at_span = at.span;
+        let first = Ident::parse_any(input)?;
+        let mut name = first.to_string();
+        let mut span = first.span();
+        while input.peek(Token![-]) {
+            input.parse::<Token![-]>()?;
+            let part = Ident::parse_any(input)?;
+            name = format!("{name}-{part}");
+            span = span.join(part.span()).unwrap_or(span);
+        }
+        let kind = match name.as_str() {
+            "strong" => CaptureKind::Clone,
+            "to-owned" => CaptureKind::ToOwned,
+            "weak-allow-none" => CaptureKind::Upgrade,
+            "weak" => return Ok(Glib::Weak(at_span, parse_target(input)?)),
+            "default-return" => {
+                let span = at.span.join(span).unwrap_or(at.span);
+                return Ok(Glib::DefaultReturn(span, Box::new(input.parse()?)));
+            }
+            _ => {
+                let mut e = Error::new(
+                    span,
+                    "expected @strong, @weak, @weak-allow-none, @to-owned, or @default-return",
+                );
+                let kinds = [
+                    "strong",
+                    "weak",
+                    "weak-allow-none",
+                    "to-owned",
+                    "default-return",
+                ];
+                if let Some(kind) = did_you_mean(&name, &kinds) {
+                    e.combine(Error::new(span, format!("did you mean `@{kind}`?")));
+                }
+                Err(e)?
+            }
+        };
+        Ok(Glib::Capture(CaptureSpec {
+            kind,
+            target: parse_target(input)?,
+            span: at_span,
+        }))
+    }
+}
+
+/// Parses `<ident>`, or `self` or a field of it followed by `as <ident>`.
+fn parse_target(input: ParseStream) -> syn::Result<CaptureTarget> {
+    let ident = Ident::parse_any(input)?;
+    let mut expr: Expr = syn::parse_quote!(#ident);
+    while input.peek(Token![.]) {
+        input.parse::<Token![.]>()?;
+        let member = input.parse::<Member>()?;
+        expr = syn::parse_quote!(#expr.#member);
+    }
+    if !input.peek(Token![as]) {
+        return match expr {
+            Expr::Path(_) if ident != "self" => Ok(CaptureTarget::Ident(ident)),
+            _ => Err(Error::new(expr.span(), "expected `as <name>`")),
+        };
+    }
+    input.parse::<Token![as]>()?;
+    let pat = Pat::Ident(PatIdent {
+        attrs: Vec::new(),
+        by_ref: None,
+        mutability: None,
+        ident: input.parse()?,
+        subpat: None,
+    });
+    Ok(CaptureTarget::Pat(Box::new(pat), Box::new(expr)))
+}
@@ -0,0 +1,201 @@
+//! The arguments of `#[with_closure(...)]`.
+
+use syn::{
+    ext::IdentExt,
+    parenthesized,
+    parse::{discouraged::Speculative, Parse, ParseStream},
+    Error, Ident, LitStr, Path, Token,
+};
+
+use crate::{
+    capture::{skip_entry, CaptureSpec, Captures, Entry},
+    report,
+};
+
+#[derive(Default)]
+pub(crate) struct Options {
+    /// Path to the `closure_attr` crate in generated code, if set.
+    pub(crate) krate: Option<Path>,
+    pub(crate) qualified_clone: bool,
+    /// Make non-`move` closures with captures plain `move`, instead of
+    /// borrowing the variables they don't capture.
+    pub(crate) auto_move: bool,
+    /// Generate code for people to read rather than the compiler, e.g. when
+    /// ejecting.
+    pub(crate) readable: bool,
+    pub(crate) sets: Vec<(Ident, Vec<CaptureSpec>)>,
+    pub(crate) defaults: Vec<CaptureSpec>,
+    /// Errors in malformed options, which are skipped.
+    pub(crate) errors: Option<Error>,
+}
+
+impl Options {
+    /// Path to the `closure_attr` crate in generated code.
+    pub(crate) fn krate(&self) -> Path {
+        match &self.krate {
+            Some(krate) => krate.clone(),
+            None if self.readable => syn::parse_quote!(closure_attr),
+            None => syn::parse_quote!(::closure_attr),
+        }
+    }
+
+    /// Expands `use <name>` and `inherit` entries into `captures`.
+    /// `enclosing` holds the captures of the enclosing closure, if any.
+    /// Entries which can't be expanded are reported and skipped.
+    pub(crate) fn resolve(
+        &self,
+        entries: Vec<Entry>,
+        enclosing: Option<&[CaptureSpec]>,
+        captures: &mut Vec<CaptureSpec>,
+    ) -> syn::Result<()> {
+        let mut error: Option<Error> = None;
+        let mut inherited = Vec::new();
+        for entry in entries {
+            match entry {
+                Entry::Capture(capture) => captures.push(capture),
+                Entry::Use(name) => match self.sets.iter().find(|(n, _)| *n == name) {
+                    Some((_, set)) => captures.extend(set.iter().cloned()),
+                    None => report(
+                        &mut error,
+                        Error::new(name.span(), format!("unknown capture set `{name}`")),
+                    ),
+                },
+                Entry::Assert(assert) => report(
+                    &mut error,
+                    Error::new(
+                        assert.span,
+                        "assert only applies to the closure it's written on",
+                    ),
+                ),
+                Entry::Inherit(inherit, except) => {
+                    let Some(enclosing) = enclosing else {
+                        report(
+                            &mut error,
+                            Error::new(inherit.span(), "inherit requires an enclosing #[closure]"),
+                        );
+                        continue;
+                    };
+                    for capture in enclosing {
+                        for ident in capture.target.idents() {
+                            if except.contains(&ident) {
+                                continue;
+                            }
+                            match capture.inherit(ident.clone(), inherit.span()) {
+                                Some(capture) => inherited.push(capture),
+                                None => report(&mut error, Error::new(
+                                    inherit.span(),
+                                    format!("cannot inherit `{ident}`; capture it explicitly or add it to except"),
+                                )),
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let explicit = captures
+            .iter()
+            .flat_map(|c| c.target.idents())
+            .collect::<Vec<_>>();
+        inherited.retain(|c: &CaptureSpec| !c.target.idents().iter().any(|i| explicit.contains(i)));
+        captures.splice(0..0, inherited);
+        error.map_or(Ok(()), Err)
+    }
+
+    /// Prepends the default captures, except those which bind an identifier
+    /// that `captures` already binds.
+    pub(crate) fn apply_defaults(&self, captures: &mut Vec<CaptureSpec>) {
+        let explicit = captures
+            .iter()
+            .flat_map(|c| c.target.idents())
+            .collect::<Vec<_>>();
+        let defaults = self
+            .defaults
+            .iter()
+            .filter(|d| !d.target.idents().iter().any(|i| explicit.contains(i)))
+            .cloned()
+            .collect::<Vec<_>>();
+        captures.splice(0..0, defaults);
+    }
+}
+
+impl Options {
+    /// Parses one option. Malformed captures within a capture set or the
+    /// defaults are reported and skipped.
+    fn option(&mut self, input: ParseStream) -> syn::Result<()> {
+        let ident = Ident::parse_any(input)?;
+        match ident.to_string().as_str() {
+            "crate" => {
+                input.parse::<Token![=]>()?;
+                // Like serde, accept a string; also accept a bare path.
+                self.krate = Some(if input.peek(LitStr) {
+                    input.parse::<LitStr>()?.parse()?
+                } else {
+                    Path::parse_mod_style(input)?
+                });
+            }
+            "qualified_clone" => self.qualified_clone = true,
+            "auto_move" => self.auto_move = true,
+            "set" => {
+                let name = Ident::parse(input)?;
+                input.parse::<Token![=]>()?;
+                let captures = self.captures(input)?;
+                if self.sets.iter().any(|(n, _)| *n == name) {
+                    return Err(Error::new(
+                        name.span(),
+                        format!("duplicate capture set `{name}`"),
+                    ));
+                }
+                self.sets.push((name, captures));
+            }
+            "default" => {
+                let mut captures = self.captures(input)?;
+                self.defaults.append(&mut captures);
+            }
+            _ => {
+                return Err(Error::new(
+                    ident.span(),
+                    "expected crate, qualified_clone, auto_move, set, or default",
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses a parenthesized capture list, keeping the valid captures.
+    fn captures(&mut self, input: ParseStream) -> syn::Result<Vec<CaptureSpec>> {
+        let paren;
+        parenthesized!(paren in input);
+        let Captures { entries, errors } = paren.parse()?;
+        if let Some(e) = errors {
+            report(&mut self.errors, e);
+        }
+        let mut captures = Vec::new();
+        if let Err(e) = self.resolve(entries, None, &mut captures) {
+            report(&mut self.errors, e);
+        }
+        Ok(captures)
+    }
+}
+
+impl Parse for Options {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut options = Options::default();
+        while !input.is_empty() {
+            // Parse on a fork so that a malformed option is skipped whole.
+            let fork = input.fork();
+            if let Err(e) = options.option(&fork) {
+                report(&mut options.errors, e);
+                skip_entry(input);
+                continue;
+            }
+            input.advance_to(&fork);
+            if !input.is_empty() {
+                if let Err(e) = input.parse::<Token![,]>() {
+                    report(&mut options.errors, e);
+                    skip_entry(input);
+                }
+            }
+        }
+        Ok(options)
+    }
+}
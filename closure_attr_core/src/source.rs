@@ -0,0 +1,66 @@
+//! Positions within source text, shared by [crate::eject] and the
+//! command-line tools which rewrite source files.
+
+use proc_macro2::LineColumn;
+use syn::Attribute;
+
+/// Converts the line and column positions of spans within `src` to byte
+/// offsets.
+pub struct Text<'a> {
+    src: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> Text<'a> {
+    pub fn new(src: &'a str) -> Self {
+        Text {
+            src,
+            line_starts: std::iter::once(0)
+                .chain(src.match_indices('\n').map(|(i, _)| i + 1))
+                .collect(),
+        }
+    }
+
+    /// The byte offset of `pos`.
+    pub fn offset(&self, pos: LineColumn) -> usize {
+        let start = self.line_start(pos.line);
+        self.src[start..]
+            .char_indices()
+            .nth(pos.column)
+            .map_or(self.src.len(), |(i, _)| start + i)
+    }
+
+    /// The byte offset of the start of a line, counting from 1.
+    pub fn line_start(&self, line: usize) -> usize {
+        self.line_starts[line - 1]
+    }
+
+    /// The number of spaces at the start of a line.
+    pub fn indent(&self, line: usize) -> usize {
+        let line = &self.src[self.line_start(line)..];
+        line.len() - line.trim_start_matches(' ').len()
+    }
+
+    /// The byte range of `range`, extended to whole lines if nothing else
+    /// shares them.
+    pub fn lines(&self, range: (LineColumn, LineColumn)) -> (usize, usize) {
+        let (start, end) = (self.offset(range.0), self.offset(range.1));
+        let before = self.src[..start].trim_end_matches([' ', '\t']);
+        let after = self.src[end..].trim_start_matches([' ', '\t']);
+        match (
+            before.is_empty() || before.ends_with('\n'),
+            after.strip_prefix('\n'),
+        ) {
+            (true, Some(rest)) => (before.len(), self.src.len() - rest.len()),
+            _ => (start, end),
+        }
+    }
+}
+
+/// Whether `attr` is `#[with_closure]`, however it was imported.
+pub fn is_with_closure(attr: &Attribute) -> bool {
+    attr.path()
+        .segments
+        .last()
+        .is_some_and(|s| s.ident == "with_closure")
+}
@@ -0,0 +1,909 @@
+//! Expansion of `#[closure(...)]` closures.
+
+#[cfg(feature = "eject")]
+use proc_macro2::LineColumn;
+use proc_macro2::{Span, TokenStream as TokenStream2, TokenTree};
+use quote::{quote, quote_spanned, ToTokens};
+use syn::{
+    spanned::Spanned, visit_mut::VisitMut, AttrStyle, Attribute, Error, Expr, ExprClosure,
+    GenericParam, Generics, Ident, ImplItemFn, Item, Local, Meta, PatIdent, Path, StmtMacro, Token,
+    TraitItemFn, TypeParamBound,
+};
+#[cfg(feature = "eject")]
+use {
+    crate::borrow::{macro_arg_uses, macro_args, macro_uses},
+    syn::{visit::Visit, ExprField, ExprPath, Macro},
+};
+
+use crate::{
+    borrow::{borrowed, deref, shadowing, Deref},
+    capture::{Assert, Bindings, CaptureKind, CaptureSpec, CaptureTarget, Captures, Entry},
+    options::Options,
+};
+
+/// Parenthesizes an expression unless it binds tighter than the
+/// operators generated code applies to it.
+fn operand(expr: &Expr) -> TokenStream2 {
+    match expr {
+        Expr::Path(_)
+        | Expr::Field(_)
+        | Expr::MethodCall(_)
+        | Expr::Call(_)
+        | Expr::Index(_)
+        | Expr::Paren(_)
+        | Expr::Tuple(_)
+        | Expr::Lit(_)
+        | Expr::Macro(_) => quote! {#expr},
+        _ => quote! {(#expr)},
+    }
+}
+
+/// `return <expr>`, or a bare `return` if `expr` is `()`.
+fn returning(span: Span, expr: &Expr) -> TokenStream2 {
+    match expr {
+        Expr::Tuple(unit) if unit.elems.is_empty() => quote_spanned! {span=> return},
+        expr => quote_spanned! {span=> return #expr},
+    }
+}
+
+/// Expands `#[closure(...)]` closures in the syntax tree it visits.
+///
+/// Embed it in another [VisitMut] pass by forwarding expressions to
+/// [ClosureVisitor::visit_expr_mut]. Errors don't stop the visit; collect
+/// them afterwards with [ClosureVisitor::take_errors].
+#[derive(Default)]
+pub struct ClosureVisitor {
+    options: Options,
+    errors: Option<Error>,
+    /// Captures of the `#[closure]`s enclosing the current expression.
+    enclosing: Vec<Vec<CaptureSpec>>,
+    /// Variables declared `mut` so far, which non-`move` closures may
+    /// borrow mutably.
+    mutable: Vec<Ident>,
+    /// The generic parameters in scope, and `Self` within an impl or trait.
+    generics: Vec<String>,
+    /// When ejecting, the closures to rewrite, innermost first. Closures
+    /// are left in place.
+    #[cfg(feature = "eject")]
+    pub(crate) ejected: Option<Vec<Ejection>>,
+}
+
+/// How to rewrite a `#[closure]` closure into plain Rust.
+#[cfg(feature = "eject")]
+pub(crate) struct Ejection {
+    /// Where the expression starts, including its attributes.
+    pub(crate) start: LineColumn,
+    /// The `#[closure]` attributes.
+    pub(crate) attrs: Vec<(LineColumn, LineColumn)>,
+    /// The closure, without its attributes.
+    pub(crate) closure: (LineColumn, LineColumn),
+    pub(crate) body: Box<Expr>,
+    /// Where to insert `move`, if the closure wasn't already.
+    pub(crate) add_move: Option<LineColumn>,
+    /// Whether to wrap the closure in a call to `assert_closure`.
+    pub(crate) assert: bool,
+    /// Statements which check bounds on the closure, bound to `closure`.
+    pub(crate) checks: TokenStream2,
+    /// Statements which go before the closure.
+    pub(crate) locals: TokenStream2,
+    /// Statements which go at the start of the body.
+    pub(crate) prelude: TokenStream2,
+    /// Uses of borrowed variables in the body to dereference.
+    pub(crate) derefs: Vec<Deref>,
+}
+
+/// Whether `body` uses `ident` other than through field accesses. If it
+/// doesn't, the closure only captures the fields it accesses. Macro
+/// arguments which parse as expressions are searched like the body; any
+/// other mention within a macro counts as a whole use.
+#[cfg(feature = "eject")]
+fn uses_whole(body: &Expr, ident: &Ident) -> bool {
+    struct Uses<'a> {
+        ident: &'a Ident,
+        whole: bool,
+    }
+
+    impl<'ast> Visit<'ast> for Uses<'_> {
+        fn visit_expr_field(&mut self, expr: &'ast ExprField) {
+            match &*expr.base {
+                Expr::Path(path) if path.path.is_ident(self.ident) => {}
+                _ => syn::visit::visit_expr_field(self, expr),
+            }
+        }
+
+        fn visit_expr_path(&mut self, expr: &'ast ExprPath) {
+            self.whole |= expr.path.is_ident(self.ident);
+        }
+
+        fn visit_macro(&mut self, mac: &'ast Macro) {
+            match macro_args(mac) {
+                Some(args) => {
+                    for arg in &args {
+                        match arg {
+                            Expr::Lit(_) => self.whole |= macro_arg_uses(arg, self.ident),
+                            arg => self.visit_expr(arg),
+                        }
+                    }
+                }
+                None => self.whole |= macro_uses(mac, self.ident),
+            }
+        }
+    }
+
+    let mut uses = Uses {
+        ident,
+        whole: false,
+    };
+    uses.visit_expr(body);
+    uses.whole
+}
+
+/// The attributes of `expr`, if it can have any.
+fn expr_attrs(expr: &mut Expr) -> Option<&mut Vec<Attribute>> {
+    macro_rules! attrs {
+        ($($variant:ident),*) => {
+            match expr {
+                $(Expr::$variant(e) => Some(&mut e.attrs),)*
+                _ => None,
+            }
+        };
+    }
+    attrs!(
+        Array, Assign, Async, Await, Binary, Block, Break, Call, Cast, Closure, Const, Continue,
+        Field, ForLoop, Group, If, Index, Infer, Let, Lit, Loop, Macro, Match, MethodCall, Paren,
+        Path, Range, Reference, Repeat, Return, Struct, Try, TryBlock, Tuple, Unary, Unsafe, While,
+        Yield
+    )
+}
+
+/// The error for a `#[closure]` attribute which isn't on a closure.
+fn stray(attr: &Attribute) -> Error {
+    Error::new_spanned(attr, "#[closure] must be placed directly on a closure")
+}
+
+/// The generic parameters `generics` declares, as bounds name them: `T`,
+/// `N`, or `'a`.
+fn params(generics: &Generics) -> impl Iterator<Item = String> + '_ {
+    generics.params.iter().map(|param| match param {
+        GenericParam::Type(t) => t.ident.to_string(),
+        GenericParam::Lifetime(l) => l.lifetime.to_string(),
+        GenericParam::Const(c) => c.ident.to_string(),
+    })
+}
+
+/// Whether `tokens` name any of `generics`.
+fn names_any(tokens: TokenStream2, generics: &[String]) -> bool {
+    let mut lifetime = false;
+    tokens.into_iter().any(|tt| {
+        let named = match &tt {
+            TokenTree::Group(group) => names_any(group.stream(), generics),
+            TokenTree::Ident(ident) if lifetime => generics.contains(&format!("'{ident}")),
+            TokenTree::Ident(ident) => generics.iter().any(|g| ident == g),
+            _ => false,
+        };
+        lifetime = matches!(&tt, TokenTree::Punct(p) if p.as_char() == '\'');
+        named
+    })
+}
+
+/// Whether `attr` is `#[closure]` or `#[closure(...)]`.
+fn is_closure_attr(attr: &Attribute) -> bool {
+    matches!(attr.style, AttrStyle::Outer) && attr.path().is_ident("closure")
+}
+
+impl ClosureVisitor {
+    /// A visitor with the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A visitor configured by the arguments of `#[with_closure(...)]`,
+    /// e.g. `crate = my_facade::closure_attr, qualified_clone`. Malformed
+    /// arguments are skipped, and their errors returned by
+    /// [take_errors](Self::take_errors).
+    pub fn with_args(args: TokenStream2) -> syn::Result<Self> {
+        let mut options: Options = syn::parse2(args)?;
+        Ok(ClosureVisitor {
+            errors: options.errors.take(),
+            options,
+            ..Self::default()
+        })
+    }
+
+    /// Sets the path to the `closure_attr` crate in generated code.
+    pub fn crate_path(mut self, krate: Path) -> Self {
+        self.options.krate = Some(krate);
+        self
+    }
+
+    /// Sets whether `clone` expands to `Clone::clone(&x)` instead of
+    /// `x.clone()`.
+    pub fn qualified_clone(mut self, qualified_clone: bool) -> Self {
+        self.options.qualified_clone = qualified_clone;
+        self
+    }
+
+    /// Sets whether closures with captures which aren't declared `move` are
+    /// made plain `move`, instead of borrowing the variables they don't
+    /// capture.
+    pub fn auto_move(mut self, auto_move: bool) -> Self {
+        self.options.auto_move = auto_move;
+        self
+    }
+
+    /// Records ejections instead of expanding, and generates readable code.
+    #[cfg(feature = "eject")]
+    pub(crate) fn start_ejecting(&mut self) {
+        self.options.readable = true;
+        self.ejected = Some(Vec::new());
+    }
+
+    /// Removes and returns the errors found so far.
+    pub fn take_errors(&mut self) -> Option<Error> {
+        self.errors.take()
+    }
+
+    /// Expands `closure` with `captures`, which are used as given; capture
+    /// sets and defaults only apply to `#[closure(...)]` attributes. Nested
+    /// `#[closure(...)]` closures within the body are expanded as well, and
+    /// may `inherit` from `captures`.
+    ///
+    /// On return `closure` holds the rewritten closure; the returned
+    /// expression evaluates to it after binding the captures.
+    pub fn transform(
+        &mut self,
+        closure: &mut ExprClosure,
+        captures: &[CaptureSpec],
+    ) -> syn::Result<Expr> {
+        let outer = self.errors.take();
+        self.check_shadowing(closure, captures);
+        let (borrows, _) = self.make_move(closure, captures);
+        self.enclosing.push(captures.to_vec());
+        // The closure's own attributes are the caller's to keep.
+        let attrs = std::mem::take(&mut closure.attrs);
+        syn::visit_mut::visit_expr_closure_mut(self, closure);
+        closure.attrs = attrs;
+        self.enclosing.pop();
+        let expr = self.expand(closure, captures.to_vec(), borrows, &[]);
+        match std::mem::replace(&mut self.errors, outer) {
+            Some(e) => Err(e),
+            None => Ok(expr),
+        }
+    }
+
+    fn report(&mut self, e: Error) {
+        crate::report(&mut self.errors, e);
+    }
+
+    /// The path to `closure_attr`, spanned by `span` so that errors in the
+    /// code which uses it point at the capture.
+    fn krate(&self, span: Span) -> TokenStream2 {
+        self.options
+            .krate()
+            .into_token_stream()
+            .into_iter()
+            .map(|mut tt| {
+                tt.set_span(span);
+                tt
+            })
+            .collect()
+    }
+
+    /// Makes `closure` `move`, so that it owns its captures. Unless
+    /// `auto_move` is set, a closure which wasn't `move` keeps borrowing the
+    /// other variables it uses: returns the statements which bind each of
+    /// them to a reference, to go before the closure, and the uses in the
+    /// body which now dereference one.
+    fn make_move(
+        &self,
+        closure: &mut ExprClosure,
+        captures: &[CaptureSpec],
+    ) -> (TokenStream2, Vec<Deref>) {
+        if closure.capture.is_some() {
+            return (quote! {}, Vec::new());
+        }
+        closure.capture = Some(Token![move](closure.or1_token.span));
+        if self.options.auto_move {
+            return (quote! {}, Vec::new());
+        }
+        let bound = captures
+            .iter()
+            .flat_map(|c| c.target.idents())
+            .collect::<Vec<_>>();
+        let mut borrows = quote! {};
+        let mut idents = Vec::new();
+        for (ident, mutably) in borrowed(closure, &bound, &self.mutable) {
+            if mutably {
+                borrows.extend(quote_spanned! {ident.span()=> let #ident = &mut #ident;});
+            } else {
+                borrows.extend(quote_spanned! {ident.span()=> let #ident = &#ident;});
+            }
+            idents.push(ident);
+        }
+        (borrows, deref(closure, &idents))
+    }
+
+    /// How to check `asserts`: the function which checks the bounds it can,
+    /// the identity with the bounds on its argument, and statements which
+    /// check the rest on `closure`. A nested function can't name the
+    /// generic parameters of the item it's in, so bounds which do are
+    /// checked by converting a reference to the closure to a trait object.
+    fn assertion(&self, asserts: &[Assert]) -> (TokenStream2, TokenStream2) {
+        let mut bounds = Vec::new();
+        let mut checks = quote! {};
+        for assert in asserts {
+            for bound in &assert.bounds {
+                if !names_any(bound.to_token_stream(), &self.generics) {
+                    bounds.push(bound);
+                    continue;
+                }
+                let ty = match bound {
+                    TypeParamBound::Lifetime(_) => {
+                        let krate = self.krate(assert.span);
+                        quote_spanned! {assert.span=> (dyn #krate::__private::Anything + #bound)}
+                    }
+                    bound => quote_spanned! {assert.span=> dyn #bound},
+                };
+                checks.extend(quote_spanned! {assert.span=> let _: &#ty = &closure;});
+            }
+        }
+        let check = match asserts.first() {
+            Some(first) if !bounds.is_empty() => quote_spanned! {first.span=>
+                fn assert_closure<F: #(#bounds)+*>(f: F) -> F {
+                    f
+                }
+            },
+            _ => quote! {},
+        };
+        (check, checks)
+    }
+
+    /// The initial value of a capture which binds by value.
+    fn value(&self, span: Span, kind: &CaptureKind, src: &TokenStream2) -> TokenStream2 {
+        if self.options.readable {
+            match kind {
+                CaptureKind::Clone | CaptureKind::CloneMut if self.options.qualified_clone => {
+                    return quote! {Clone::clone(&#src)};
+                }
+                CaptureKind::ToOwned => return quote! {#src.to_owned()},
+                CaptureKind::Into(_) => return quote! {#src.into()},
+                CaptureKind::TryClone(Some(expr)) | CaptureKind::TryCloneMut(Some(expr)) => {
+                    return quote! {
+                        match #src.try_clone() {
+                            Ok(value) => value,
+                            Err(_) => #expr,
+                        }
+                    };
+                }
+                _ => {}
+            }
+        }
+        match kind {
+            CaptureKind::Clone | CaptureKind::CloneMut if self.options.qualified_clone => {
+                quote_spanned! {span=> ::core::clone::Clone::clone(&#src)}
+            }
+            CaptureKind::Clone | CaptureKind::CloneMut => quote_spanned! {span=> #src.clone()},
+            CaptureKind::Ref => quote_spanned! {span=> &#src},
+            CaptureKind::RefMut => quote_spanned! {span=> &mut #src},
+            CaptureKind::Share => {
+                let krate = self.krate(span);
+                quote_spanned! {span=> #krate::Share::share(&#src)}
+            }
+            CaptureKind::ToOwned => {
+                let krate = self.krate(span);
+                quote_spanned! {span=>
+                {
+                    use #krate::__private::ToOwned as _;
+                    #src.to_owned()
+                }
+                }
+            }
+            CaptureKind::Into(_) => quote_spanned! {span=> ::core::convert::Into::into(#src)},
+            CaptureKind::Via(path) => quote_spanned! {span=> #path(&#src)},
+            CaptureKind::TryClone(Some(expr)) | CaptureKind::TryCloneMut(Some(expr)) => {
+                quote_spanned! {span=>
+                    match #src.try_clone() {
+                        ::core::result::Result::Ok(value) => value,
+                        ::core::result::Result::Err(_) => #expr,
+                    }
+                }
+            }
+            CaptureKind::TryClone(None) | CaptureKind::TryCloneMut(None) => {
+                quote_spanned! {span=> #src.try_clone()?}
+            }
+            _ => quote_spanned! {span=> #src},
+        }
+    }
+
+    /// Stores the value behind `reference` for kinds which only keep a
+    /// derived value, e.g. a weak pointer.
+    fn store(
+        &self,
+        span: Span,
+        kind: &CaptureKind,
+        reference: TokenStream2,
+        ident: &Ident,
+    ) -> TokenStream2 {
+        let krate = self.krate(span);
+        match kind {
+            CaptureKind::Using(ty, _) | CaptureKind::UsingMut(ty, _) => quote_spanned! {span=>
+                let #ident = #krate::Using::<#ty, _>::prepare(#reference);
+            },
+            _ if self.options.readable => quote_spanned! {span=>
+                let #ident = #krate::Downgrade::downgrade(#reference);
+            },
+            // Asserts the capture implements `Downgrade`, so that rustc
+            // reports a single error, at the capture.
+            _ => quote_spanned! {span=>
+                let #ident = #krate::__private::downgrade(#reference);
+            },
+        }
+    }
+
+    /// Code which runs at the start of each call.
+    fn enter(&self, span: Span, kind: &CaptureKind, ident: &Ident) -> TokenStream2 {
+        let krate = self.krate(span);
+        let upgrade = if self.options.readable {
+            quote_spanned! {span=> #krate::Upgrade::upgrade}
+        } else {
+            quote_spanned! {span=> #krate::__private::upgrade}
+        };
+        match kind {
+            CaptureKind::Fail(expr) => {
+                let fail = returning(span, expr);
+                quote_spanned! {span=>
+                    let Some(#ident) = #upgrade(&#ident) else {
+                        #fail;
+                    };
+                }
+            }
+            CaptureKind::Upgrade => quote_spanned! {span=>
+                let #ident = #upgrade(&#ident);
+            },
+            CaptureKind::Panic if self.options.readable => quote! {
+                let Some(#ident) = #upgrade(&#ident) else {
+                    panic!("Closure failed to upgrade weak pointer");
+                };
+            },
+            CaptureKind::Panic => quote_spanned! {span=>
+                let Some(#ident) = #upgrade(&#ident) else {
+                    ::core::panic!("Closure failed to upgrade weak pointer");
+                };
+            },
+            CaptureKind::Enter(expr) | CaptureKind::EnterMut(expr) => {
+                let binding = match kind {
+                    CaptureKind::EnterMut(_) => quote_spanned! {span=> mut #ident},
+                    _ => quote_spanned! {span=> #ident},
+                };
+                let fail = returning(span, expr);
+                quote_spanned! {span=>
+                    let Some(#binding) = #krate::Enter::enter(&#ident) else {
+                        #fail;
+                    };
+                }
+            }
+            CaptureKind::Using(_, expr) | CaptureKind::UsingMut(_, expr) => {
+                let binding = match kind {
+                    CaptureKind::UsingMut(..) => quote_spanned! {span=> mut #ident},
+                    _ => quote_spanned! {span=> #ident},
+                };
+                let fail = match expr {
+                    Some(expr) => {
+                        let fail = returning(span, expr);
+                        quote_spanned! {span=> ::core::ops::ControlFlow::Break(_) => #fail,}
+                    }
+                    None => {
+                        quote_spanned! {span=> ::core::ops::ControlFlow::Break(value) => return value,}
+                    }
+                };
+                quote_spanned! {span=>
+                    let #binding = match #krate::Using::enter(&#ident) {
+                        ::core::ops::ControlFlow::Continue(value) => value,
+                        #fail
+                    };
+                }
+            }
+            _ => quote! {},
+        }
+    }
+
+    /// Removes the `#[closure]` attributes from a closure and resolves its
+    /// captures and assertions. `None` if it has no `#[closure(...)]`
+    /// attribute.
+    fn take_captures(
+        &mut self,
+        closure: &mut ExprClosure,
+    ) -> Option<(Vec<CaptureSpec>, Vec<Assert>)> {
+        let mut captures = Vec::new();
+        let mut explicit = Vec::new();
+        let mut asserts = Vec::new();
+        let mut found = false;
+        closure.attrs = closure
+            .attrs
+            .drain(..)
+            .filter(|a| {
+                if let AttrStyle::Outer = a.style {
+                    match &a.meta {
+                        Meta::Path(p) => {
+                            if let Some(ident) = p.get_ident() {
+                                if ident == "closure" {
+                                    self.report(Error::new(
+                                        a.span(),
+                                        "closure attribute must have arguments",
+                                    ));
+                                    return false;
+                                }
+                            }
+                        }
+                        Meta::List(l) => {
+                            if let Some(ident) = l.path.get_ident() {
+                                if ident == "closure" {
+                                    found = true;
+                                    let ct = match syn::parse2::<Captures>(l.tokens.clone()) {
+                                        Ok(v) => v,
+                                        Err(e) => {
+                                            self.report(e);
+                                            return false;
+                                        }
+                                    };
+                                    if let Some(e) = ct.errors {
+                                        self.report(e);
+                                    }
+                                    let mut entries = Vec::new();
+                                    for entry in ct.entries {
+                                        match entry {
+                                            Entry::Assert(assert) => asserts.push(assert),
+                                            Entry::Capture(c) => {
+                                                explicit.push(c.clone());
+                                                entries.push(Entry::Capture(c));
+                                            }
+                                            entry => entries.push(entry),
+                                        }
+                                    }
+                                    let enclosing = self.enclosing.last().map(Vec::as_slice);
+                                    if let Err(e) =
+                                        self.options.resolve(entries, enclosing, &mut captures)
+                                    {
+                                        self.report(e);
+                                    }
+                                    return false;
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                true
+            })
+            .collect();
+
+        if !found {
+            return None;
+        }
+        // Only captures written on the closure; shadowing an inherited or
+        // default capture is fine.
+        self.check_shadowing(closure, &explicit);
+        self.options.apply_defaults(&mut captures);
+        Some((captures, asserts))
+    }
+
+    /// Reports captures which `closure` shadows before using them.
+    fn check_shadowing(&mut self, closure: &ExprClosure, captures: &[CaptureSpec]) {
+        for ident in captures.iter().flat_map(|c| c.target.idents()) {
+            if let Some(by) = shadowing(closure, &ident) {
+                self.report(Error::new(
+                    ident.span(),
+                    format!("`{ident}` is shadowed by {by} before it's used, so capturing it has no effect"),
+                ));
+            }
+        }
+    }
+
+    /// Removes `#[closure]` attributes from something other than a closure,
+    /// reporting each, so that rustc doesn't complain about attributes on
+    /// expressions instead.
+    fn strip_stray(&mut self, attrs: &mut Vec<Attribute>) {
+        attrs.retain(|a| {
+            if !is_closure_attr(a) {
+                return true;
+            }
+            self.report(stray(a));
+            false
+        });
+    }
+
+    /// Binds `captures` around `closure`, after `borrows` from
+    /// [Self::make_move], rewriting its body to use them, and checks
+    /// `asserts`.
+    fn expand(
+        &mut self,
+        closure: &mut ExprClosure,
+        captures: Vec<CaptureSpec>,
+        borrows: TokenStream2,
+        asserts: &[Assert],
+    ) -> Expr {
+        let span = closure.span();
+        let (locals, whole, upgrade) = self.bindings(captures);
+
+        // Force capture of whole variables without preventing unused warnings.
+        if !whole.is_empty() {
+            let body = closure.body.clone();
+            *closure.body = Expr::Verbatim(quote_spanned! {span=>
+                {
+                    #[allow(unreachable_code, clippy::never_loop)]
+                    loop {
+                        break;
+                        #(let _ = &#whole;)*
+                    }
+                    #body
+                }
+            });
+        }
+
+        if !upgrade.is_empty() {
+            let body = closure.body.clone();
+            *closure.body = Expr::Verbatim(quote_spanned! {span=>
+                {
+                    #upgrade
+                    #body
+                }
+            });
+        }
+
+        let (check, checks) = self.assertion(asserts);
+        let mut closure = closure.to_token_stream();
+        if let Some(assert) = asserts.first() {
+            if !check.is_empty() {
+                closure = quote_spanned! {assert.span=> assert_closure(#closure)};
+            }
+            if !checks.is_empty() {
+                closure = quote_spanned! {assert.span=> let closure = #closure; #checks closure};
+            }
+        }
+        Expr::Verbatim(quote_spanned! {span=>
+            {
+                #borrows
+                #locals
+                #check
+                #closure
+            }
+        })
+    }
+
+    /// Records how to eject `closure`, whose expression starts at `start`.
+    /// `attrs` are the ranges of its `#[closure]` attributes.
+    #[cfg(feature = "eject")]
+    fn eject(
+        &mut self,
+        closure: &ExprClosure,
+        captures: Vec<CaptureSpec>,
+        (start, attrs): (LineColumn, Vec<(LineColumn, LineColumn)>),
+        add_move: Option<LineColumn>,
+        (borrows, derefs): (TokenStream2, Vec<Deref>),
+        asserts: &[Assert],
+    ) {
+        let (mut locals, whole, mut prelude) = self.bindings(captures);
+        let (check, checks) = self.assertion(asserts);
+        locals = quote! {#borrows #locals #check};
+        for ident in whole {
+            if !uses_whole(&closure.body, &ident) {
+                prelude.extend(quote! {let _ = &#ident;});
+            }
+        }
+        let mut bare = closure.clone();
+        bare.attrs.clear();
+        if let Some(ejected) = &mut self.ejected {
+            ejected.push(Ejection {
+                start,
+                attrs,
+                closure: (bare.span().start(), bare.span().end()),
+                body: closure.body.clone(),
+                add_move,
+                assert: !check.is_empty(),
+                checks,
+                locals,
+                prelude,
+                derefs,
+            });
+        }
+    }
+
+    /// The statements which bind `captures` before the closure, the
+    /// variables it must capture whole, and the statements which start each
+    /// call.
+    fn bindings(&self, captures: Vec<CaptureSpec>) -> (TokenStream2, Vec<Ident>, TokenStream2) {
+        let mut locals = quote! {};
+        let mut whole = Vec::new();
+        let mut upgrade = quote! {};
+        for cap in captures {
+            let span = cap.span;
+            let idents = cap.target.idents();
+            match &cap.kind {
+                CaptureKind::Weak
+                | CaptureKind::Upgrade
+                | CaptureKind::Fail(_)
+                | CaptureKind::Panic
+                | CaptureKind::Using(..)
+                | CaptureKind::UsingMut(..) => match &cap.target {
+                    CaptureTarget::Ident(ident) => {
+                        locals.extend(self.store(
+                            span,
+                            &cap.kind,
+                            quote_spanned! {span=> &#ident},
+                            ident,
+                        ));
+                    }
+                    CaptureTarget::Pat(pat, expr) => {
+                        let expr = operand(expr);
+                        locals.extend(quote_spanned! {span=> let #pat = &#expr;});
+                        for ident in &idents {
+                            locals.extend(self.store(span, &cap.kind, quote! {#ident}, ident));
+                        }
+                    }
+                },
+                CaptureKind::Enter(_) | CaptureKind::EnterMut(_) => {
+                    // The body borrows the whole variable when it enters it.
+                    if let CaptureTarget::Pat(pat, expr) = &cap.target {
+                        locals.extend(quote_spanned! {span=> let #pat = #expr;});
+                    }
+                }
+                kind => {
+                    let is_mut = matches!(
+                        kind,
+                        CaptureKind::CloneMut | CaptureKind::MoveMut | CaptureKind::TryCloneMut(_)
+                    );
+                    let (pat, src) = match &cap.target {
+                        CaptureTarget::Ident(ident) if is_mut => {
+                            (quote! {mut #ident}, quote! {#ident})
+                        }
+                        CaptureTarget::Ident(ident) => (quote! {#ident}, quote! {#ident}),
+                        CaptureTarget::Pat(pat, expr) => {
+                            let mut pat = pat.clone();
+                            Bindings {
+                                make_mut: is_mut,
+                                idents: Vec::new(),
+                            }
+                            .visit_pat_mut(&mut pat);
+                            (quote! {#pat}, operand(expr))
+                        }
+                    };
+                    let ty = match kind {
+                        CaptureKind::Into(Some(ty)) => Some(quote_spanned! {span=> : #ty}),
+                        _ => None,
+                    };
+                    let value = self.value(span, kind, &src);
+                    locals.extend(quote_spanned! {span=> let #pat #ty = #value;});
+                    whole.extend(idents.iter().cloned());
+                }
+            }
+            for ident in &idents {
+                upgrade.extend(self.enter(span, &cap.kind, ident));
+            }
+        }
+
+        (locals, whole, upgrade)
+    }
+}
+
+impl VisitMut for ClosureVisitor {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        let Expr::Closure(closure) = expr else {
+            if let Some(attrs) = expr_attrs(expr) {
+                self.strip_stray(attrs);
+            }
+            syn::visit_mut::visit_expr_mut(self, expr);
+            return;
+        };
+
+        #[cfg(feature = "eject")]
+        let source = (
+            closure.span().start(),
+            closure
+                .attrs
+                .iter()
+                .filter(|a| is_closure_attr(a))
+                .map(|a| (a.span().start(), a.span().end()))
+                .collect(),
+        );
+
+        // Captures are resolved before visiting the body so that nested
+        // closures can inherit them.
+        let taken = self.take_captures(closure);
+        let has_captures = taken.as_ref().is_some_and(|(c, _)| !c.is_empty());
+        #[cfg(feature = "eject")]
+        let add_move =
+            (has_captures && closure.capture.is_none()).then(|| closure.or1_token.span.start());
+        // Before visiting the body, which expands nested closures within it.
+        #[cfg_attr(not(feature = "eject"), allow(unused_variables))]
+        let (borrows, derefs) = match &taken {
+            Some((captures, _)) if has_captures => self.make_move(closure, captures),
+            _ => (quote! {}, Vec::new()),
+        };
+        if let Some((captures, _)) = &taken {
+            self.enclosing.push(captures.clone());
+        }
+        syn::visit_mut::visit_expr_closure_mut(self, closure);
+        let Some((captures, asserts)) = taken else {
+            return;
+        };
+        self.enclosing.pop();
+
+        if captures.is_empty() && asserts.is_empty() {
+            return;
+        }
+        #[cfg(feature = "eject")]
+        if self.ejected.is_some() {
+            self.eject(
+                closure,
+                captures,
+                source,
+                add_move,
+                (borrows, derefs),
+                &asserts,
+            );
+            return;
+        }
+        *expr = self.expand(closure, captures, borrows, &asserts);
+    }
+    // Items nested in a function can't name its generic parameters.
+    fn visit_item_mut(&mut self, item: &mut Item) {
+        let outer = std::mem::take(&mut self.generics);
+        match item {
+            Item::Fn(f) => self.generics.extend(params(&f.sig.generics)),
+            Item::Impl(i) => {
+                self.generics.extend(params(&i.generics));
+                self.generics.push("Self".to_string());
+            }
+            Item::Trait(t) => {
+                self.generics.extend(params(&t.generics));
+                self.generics.push("Self".to_string());
+            }
+            _ => {}
+        }
+        syn::visit_mut::visit_item_mut(self, item);
+        self.generics = outer;
+    }
+
+    // A method may be visited without its impl or trait, whose generic
+    // parameters are then unknown.
+    fn visit_impl_item_fn_mut(&mut self, f: &mut ImplItemFn) {
+        let len = self.generics.len();
+        self.generics.extend(params(&f.sig.generics));
+        self.generics.push("Self".to_string());
+        syn::visit_mut::visit_impl_item_fn_mut(self, f);
+        self.generics.truncate(len);
+    }
+
+    fn visit_trait_item_fn_mut(&mut self, f: &mut TraitItemFn) {
+        let len = self.generics.len();
+        self.generics.extend(params(&f.sig.generics));
+        self.generics.push("Self".to_string());
+        syn::visit_mut::visit_trait_item_fn_mut(self, f);
+        self.generics.truncate(len);
+    }
+
+    fn visit_pat_ident_mut(&mut self, pat: &mut PatIdent) {
+        if pat.by_ref.is_none() && pat.mutability.is_some() {
+            self.mutable.push(pat.ident.clone());
+        }
+        syn::visit_mut::visit_pat_ident_mut(self, pat);
+    }
+
+    fn visit_local_mut(&mut self, local: &mut Local) {
+        self.strip_stray(&mut local.attrs);
+        syn::visit_mut::visit_local_mut(self, local);
+    }
+
+    fn visit_stmt_macro_mut(&mut self, stmt: &mut StmtMacro) {
+        self.strip_stray(&mut stmt.attrs);
+        syn::visit_mut::visit_stmt_macro_mut(self, stmt);
+    }
+
+    // Anywhere else, e.g. on an item or a match arm, the attribute can't be
+    // removed, but is still worth explaining.
+    fn visit_attribute_mut(&mut self, attr: &mut Attribute) {
+        if is_closure_attr(attr) {
+            self.report(stray(attr));
+        }
+    }
+}
@@ -1,4 +1,6 @@
-use closure_attr_core::with_closure;
+use closure_attr_core::{
+    closure, transform_closure, with_closure, CaptureKind, CaptureSpec, ClosureVisitor,
+};
 use proc_macro2::TokenTree as TT;
 use proc_macro2::{LineColumn, Spacing, TokenStream};
 use quote::quote;
@@ -82,12 +84,45 @@ fn errors() {
             }
         )),
         quote! {
-            compile_error!{ (1usize,0usize), (1usize,0usize), "with_closure attribute takes no arguments" }
+            compile_error!{ (1usize,0usize), (1usize,0usize), "expected crate, qualified_clone, auto_move, set, or default" }
             fn x() {}
         }
         .to_string()
     );
 
+    assert_eq!(
+        annotate_errors(with_closure(
+            r#"set a = (clone x), set a = ()"#.parse().unwrap(),
+            quote! {
+                fn x() {}
+            }
+        )),
+        quote! {
+            compile_error!{ (1usize,23usize), (1usize,24usize), "duplicate capture set `a`" }
+            fn x() {}
+        }
+        .to_string()
+    );
+
+    assert_eq!(
+        annotate_errors(with_closure(
+            quote! {},
+            r#"fn f() {
+                #[closure(use a, clone x)] move ||();
+            }"#.parse().unwrap()
+        )),
+        quote! {
+            compile_error!{ (2usize,30usize), (2usize,31usize), "unknown capture set `a`" }
+            fn f() {
+                {
+                    let x = x.clone();
+                    move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &x;} ()}
+                };
+            }
+        }
+        .to_string()
+    );
+
     assert_eq!(
         annotate_errors(with_closure(
             quote! {},
@@ -110,7 +145,7 @@ fn errors() {
             }"#.parse().unwrap()
         )),
         quote! {
-            compile_error!{ (2usize,26usize), (2usize,27usize), "expected clone, clone mut, ref, ref mut, move, move mut, weak, fail, or panic (1)" }
+            compile_error!{ (2usize,26usize), (2usize,27usize), "expected clone, clone mut, ref, ref mut, move, move mut, weak, upgrade, fail, panic, enter, using, to_owned, into, via, try_clone, or share (1)" }
             fn f() {| |();}
         }
         .to_string()
@@ -124,7 +159,7 @@ fn errors() {
             }"#.parse().unwrap()
         )),
         quote! {
-            compile_error!{ (2usize,26usize), (2usize,27usize), "expected clone, clone mut, ref, ref mut, move, move mut, weak, fail, or panic (2)" }
+            compile_error!{ (2usize,26usize), (2usize,27usize), "expected clone, clone mut, ref, ref mut, move, move mut, weak, upgrade, fail, panic, enter, using, to_owned, into, via, try_clone, or share (2)" }
             fn f() {| |();}
         }
         .to_string()
@@ -138,7 +173,7 @@ fn errors() {
             }"#.parse().unwrap()
         )),
         quote! {
-            compile_error!{ (2usize,26usize), (2usize,29usize), "expected clone, clone mut, ref, ref mut, move, move mut, weak, fail, or panic (2)" }
+            compile_error!{ (2usize,26usize), (2usize,29usize), "expected clone, clone mut, ref, ref mut, move, move mut, weak, upgrade, fail, panic, enter, using, to_owned, into, via, try_clone, or share (2)" }
             fn f() {| |();}
         }
         .to_string()
@@ -152,7 +187,7 @@ fn errors() {
             }"#.parse().unwrap()
         )),
         quote! {
-            compile_error!{ (2usize,26usize), (2usize,30usize), "expected clone, clone mut, ref, ref mut, move, move mut, weak, fail, or panic (2)" }
+            compile_error!{ (2usize,26usize), (2usize,30usize), "expected clone, clone mut, ref, ref mut, move, move mut, weak, upgrade, fail, panic, enter, using, to_owned, into, via, try_clone, or share (2)" }
             fn f() {| |();}
         }
         .to_string()
@@ -176,179 +211,282 @@ fn errors() {
         annotate_errors(with_closure(
             quote! {},
             r#"fn f() {
-                #[closure(clone mut x)] ||();
-            }"#.parse().unwrap()
+                #[closure(clone mut x y)] move ||();
+            }"#
+            .parse()
+            .unwrap()
         )),
         quote! {
-            compile_error!{ (2usize,40usize), (2usize,44usize), "closure must be declared with `move`" }
+            compile_error!{ (2usize,38usize), (2usize,39usize), "expected `,`" }
             fn f() {
                 {
                     let mut x = x.clone();
-                    | | {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &x;} ()}
+                    move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &x;} ()}
                 };
             }
         }
         .to_string()
     );
 
+    // Attributes which aren't on a closure are reported, and removed where
+    // they can be.
     assert_eq!(
         annotate_errors(with_closure(
             quote! {},
             r#"fn f() {
-                #[closure(clone mut x y)] move ||();
+                let a = #[closure(clone x)] make(x);
+                #[closure(clone x)] let b = || x;
+                #[closure(clone x)] fn g() {}
             }"#
             .parse()
             .unwrap()
         )),
         quote! {
-            compile_error!{ (2usize,38usize), (2usize,39usize), "expected `,`" }
-            fn f() {move | |();}
+            compile_error!{ (2usize,25usize), (2usize,43usize), "#[closure] must be placed directly on a closure" }
+            compile_error!{ (3usize,17usize), (3usize,35usize), "#[closure] must be placed directly on a closure" }
+            compile_error!{ (4usize,17usize), (4usize,35usize), "#[closure] must be placed directly on a closure" }
+            fn f() {
+                let a = make(x);
+                let b = | | x;
+                #[closure(clone x)] fn g() {}
+            }
         }
         .to_string()
     );
-}
 
-#[test]
-fn no_change() {
+    // Each malformed entry is reported; the valid ones are kept.
     assert_eq!(
-        with_closure(
+        annotate_errors(with_closure(
             quote! {},
             r#"fn f() {
-                #[closure()] move ||();
+                #[closure(clone a, frob b, ref c d e, fail(1, 2) x, move f)] move ||();
             }"#
             .parse()
             .unwrap()
-        )
-        .to_string(),
-        quote! {fn f() {
-            move | | ();
-        }}
+        )),
+        quote! {
+            compile_error!{ (2usize,35usize), (2usize,39usize), "expected clone, clone mut, ref, ref mut, move, move mut, weak, upgrade, fail, panic, enter, using, to_owned, into, via, try_clone, or share (2)" }
+            compile_error!{ (2usize,49usize), (2usize,50usize), "expected `,`" }
+            compile_error!{ (2usize,60usize), (2usize,61usize), "expected end of expression" }
+            fn f() {
+                {
+                    let a = a.clone();
+                    let c = &c;
+                    let f = f;
+                    move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &a; let _ = &c; let _ = &f;} ()}
+                };
+            }
+        }
         .to_string()
     );
 }
 
+// The start of each token named `name`, wherever it's nested.
+fn starts(stream: TokenStream, name: &str) -> Vec<(usize, usize)> {
+    let mut found = Vec::new();
+    for tt in stream {
+        match &tt {
+            TT::Group(g) => found.extend(starts(g.stream(), name)),
+            TT::Ident(i) if i == name => {
+                let LineColumn { line, column } = i.span().start();
+                found.push((line, column));
+            }
+            _ => {}
+        }
+    }
+    found
+}
+
 #[test]
-fn clone() {
+fn capture_spans() {
+    let out = with_closure(
+        quote! {},
+        r#"fn f() {
+            #[closure(clone a, weak b, fail(0) c, to_owned d)] move || ();
+        }"#
+        .parse()
+        .unwrap(),
+    );
+    // Each capture's code is spanned by its capture type.
+    assert_eq!(starts(out.clone(), "clone"), [(2, 22)]);
+    assert_eq!(starts(out.clone(), "downgrade"), [(2, 31), (2, 39)]);
+    assert_eq!(starts(out.clone(), "upgrade"), [(2, 39)]);
+    assert_eq!(starts(out.clone(), "to_owned"), [(2, 50)]);
     assert_eq!(
-        with_closure(
-            quote! {},
-            r#"fn f() {
-                #[closure(clone c)] move ||();
-            }"#
-            .parse()
-            .unwrap()
-        )
-        .to_string(),
-        quote! {fn f() {
-            {
-                let c = c.clone();
-                move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break; let _=&c;} ()}
-            };
-        }}
-        .to_string()
+        starts(out, "closure_attr"),
+        [(2, 31), (2, 39), (2, 50), (2, 39)]
     );
 }
 
 #[test]
-fn fn_in_mod() {
+fn missing_with_closure() {
     assert_eq!(
-        with_closure(
-            quote! {},
-            r#"mod m{ fn f() {
-                #[closure(clone c)] move ||();
-            }}"#
-            .parse()
-            .unwrap()
-        )
-        .to_string(),
-        quote! {mod m{
-            fn f() {
-                {
-                    let c = c.clone();
-                    move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break; let _=&c;} ()}
-                };
-            }
-        }}
+        closure(quote! {clone a}, quote! {fn f() {}}).to_string(),
+        quote! {
+            ::core::compile_error! { "missing #[with_closure] on enclosing item" }
+            fn f() {}
+        }
         .to_string()
     );
 }
 
 #[test]
-fn closure_in_var() {
+fn shadowing() {
     assert_eq!(
-        with_closure(
+        annotate_errors(with_closure(
             quote! {},
             r#"fn f() {
-                let clos = #[closure(clone c)] move ||();
+                #[closure(clone a, clone b)] move |a| b;
+                #[closure(clone a, clone b)] move || { let (a, c) = (1, 2); a + b };
+                #[closure(clone a)] move || { let b = a; let a = 1; a + b };
+                #[closure(clone a)] move || { let a = a + 1; a };
             }"#
             .parse()
             .unwrap()
-        )
-        .to_string(),
+        )),
         quote! {
-            fn f()
-            {
-                let clos = {
-                    let c = c.clone();
-                    move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break; let _=&c;} ()}
+            compile_error!{ (2usize,32usize), (2usize,33usize), "`a` is shadowed by a parameter before it's used, so capturing it has no effect" }
+            compile_error!{ (3usize,32usize), (3usize,33usize), "`a` is shadowed by a `let` before it's used, so capturing it has no effect" }
+            fn f() {
+                {
+                    let a = a.clone();
+                    let b = b.clone();
+                    move |a| {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &a; let _ = &b;} b}
+                };
+                {
+                    let a = a.clone();
+                    let b = b.clone();
+                    move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &a; let _ = &b;} { let (a, c) = (1, 2); a + b }}
+                };
+                {
+                    let a = a.clone();
+                    move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &a;} { let b = a; let a = 1; a + b }}
+                };
+                {
+                    let a = a.clone();
+                    move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &a;} { let a = a + 1; a }}
                 };
             }
         }
         .to_string()
     );
+
+    // A macro which mentions the capture may use it.
+    assert!(!annotate_errors(with_closure(
+        quote! {},
+        r#"fn f() {
+                #[closure(clone x)] move || { show!(x => 2); let x = 5; x };
+            }"#
+        .parse()
+        .unwrap()
+    ))
+    .contains("compile_error"));
+
+    // Inherited captures may be shadowed.
+    assert!(!annotate_errors(with_closure(
+        quote! {},
+        r#"fn f() {
+                #[closure(clone a)] move || #[closure(inherit)] move |a| a;
+            }"#
+        .parse()
+        .unwrap()
+    ))
+    .contains("compile_error"));
 }
 
 #[test]
-fn closure_in_call() {
+fn suggestions() {
+    let hints = |captures: &str| {
+        let out = with_closure(
+            quote! {},
+            format!("fn f() {{ #[closure({captures})] move || (); }}")
+                .parse()
+                .unwrap(),
+        );
+        let file = syn::parse2::<syn::File>(out).unwrap();
+        file.items
+            .iter()
+            .filter_map(|item| match item {
+                syn::Item::Macro(m) => Some(
+                    syn::parse2::<syn::LitStr>(m.mac.tokens.clone())
+                        .ok()?
+                        .value(),
+                ),
+                _ => None,
+            })
+            .filter(|msg| !msg.starts_with("expected"))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(hints("clon x"), ["did you mean `clone`?"]);
+    assert_eq!(hints("weakk x"), ["did you mean `weak`?"]);
+    assert_eq!(hints("ref_mut x"), ["did you mean `ref mut`?"]);
+    assert_eq!(hints("failed(0) x"), ["did you mean `fail`?"]);
+    assert_eq!(hints("mut clone x"), ["write `clone mut`, not `mut clone`"]);
+    assert_eq!(hints("mut ref x"), ["write `ref mut`, not `mut ref`"]);
+    assert_eq!(
+        hints("mut enter(0) x"),
+        ["write `enter(...) mut`, not `mut enter(...)`"]
+    );
+    assert_eq!(
+        hints("@weak-alow-none x"),
+        ["did you mean `@weak-allow-none`?"]
+    );
+    assert_eq!(
+        hints("clon x, weakk y"),
+        ["did you mean `clone`?", "did you mean `weak`?"]
+    );
+    assert!(hints("frobnicate x").is_empty());
+    assert!(hints("x").is_empty());
+}
+
+#[test]
+fn no_change() {
     assert_eq!(
         with_closure(
             quote! {},
             r#"fn f() {
-                callit(#[closure(clone c)] move ||());
+                #[closure()] move ||();
             }"#
             .parse()
             .unwrap()
         )
         .to_string(),
         quote! {fn f() {
-            callit({
-                let c = c.clone();
-                move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break; let _=&c;} ()}
-            });
+            move | | ();
         }}
         .to_string()
     );
 }
 
 #[test]
-fn immediate_call() {
+fn clone() {
     assert_eq!(
         with_closure(
             quote! {},
             r#"fn f() {
-                (#[closure(clone c)] move ||())();
+                #[closure(clone c)] move ||();
             }"#
             .parse()
             .unwrap()
         )
         .to_string(),
         quote! {fn f() {
-            ({
+            {
                 let c = c.clone();
                 move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break; let _=&c;} ()}
-            })();
+            };
         }}
         .to_string()
     );
 }
 
 #[test]
-fn all_but_weak() {
+fn qualified_clone() {
     assert_eq!(
         with_closure(
-            quote! {},
+            quote! {qualified_clone},
             r#"fn f() {
-                #[closure(clone c, clone mut cm, ref r, ref mut rm, move m, move mut mm)] move ||();
+                #[closure(clone c, clone mut m)] move ||();
             }"#
             .parse()
             .unwrap()
@@ -356,25 +494,9 @@ fn all_but_weak() {
         .to_string(),
         quote! {fn f() {
             {
-                let c = c.clone();
-                let mut cm = cm.clone();
-                let r = &r;
-                let rm = &mut rm;
-                let m = m;
-                let mut mm = mm;
-                move | | {
-                    #[allow(unreachable_code, clippy::never_loop)]
-                    loop {
-                        break;
-                        let _ = &c;
-                        let _ = &cm;
-                        let _ = &r;
-                        let _ = &rm;
-                        let _ = &m;
-                        let _ = &mm;
-                    }
-                    ()
-                }
+                let c = ::core::clone::Clone::clone(&c);
+                let mut m = ::core::clone::Clone::clone(&m);
+                move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break; let _=&c; let _=&m;} ()}
             };
         }}
         .to_string()
@@ -382,12 +504,12 @@ fn all_but_weak() {
 }
 
 #[test]
-fn all_but_weak_with_args() {
+fn auto_move() {
     assert_eq!(
         with_closure(
-            quote! {},
+            quote! {auto_move},
             r#"fn f() {
-                #[closure(clone c, clone mut cm, ref r, ref mut rm, move m, move mut mm)] move |a, b:i32, mut c|();
+                #[closure(clone x)] |a| x + a;
             }"#
             .parse()
             .unwrap()
@@ -395,25 +517,8 @@ fn all_but_weak_with_args() {
         .to_string(),
         quote! {fn f() {
             {
-                let c = c.clone();
-                let mut cm = cm.clone();
-                let r = &r;
-                let rm = &mut rm;
-                let m = m;
-                let mut mm = mm;
-                move |a, b:i32, mut c| {
-                    #[allow(unreachable_code, clippy::never_loop)]
-                    loop {
-                        break;
-                        let _ = &c;
-                        let _ = &cm;
-                        let _ = &r;
-                        let _ = &rm;
-                        let _ = &m;
-                        let _ = &mm;
-                    }
-                    ()
-                }
+                let x = x.clone();
+                move |a| {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &x;} x + a}
             };
         }}
         .to_string()
@@ -421,12 +526,16 @@ fn all_but_weak_with_args() {
 }
 
 #[test]
-fn all_but_weak_with_ret() {
+fn non_move() {
     assert_eq!(
         with_closure(
             quote! {},
             r#"fn f() {
-                #[closure(clone c, clone mut cm, ref r, ref mut rm, move m, move mut mm)] move |a, b:i32, mut c| {return 7;};
+                #[closure(clone x)] |a: &[u8], (b, c)| {
+                    let d = a.len() + b + c;
+                    println!("{x} {big:?} {{d}}", d = d + extra);
+                    big.len() + Some(x).map(|e| e + d).unwrap() + N
+                };
             }"#
             .parse()
             .unwrap()
@@ -434,25 +543,13 @@ fn all_but_weak_with_ret() {
         .to_string(),
         quote! {fn f() {
             {
-                let c = c.clone();
-                let mut cm = cm.clone();
-                let r = &r;
-                let rm = &mut rm;
-                let m = m;
-                let mut mm = mm;
-                move |a, b:i32, mut c| {
-                    #[allow(unreachable_code, clippy::never_loop)]
-                    loop {
-                        break;
-                        let _ = &c;
-                        let _ = &cm;
-                        let _ = &r;
-                        let _ = &rm;
-                        let _ = &m;
-                        let _ = &mm;
-                    }
-                    { return 7; }
-                }
+                let big = &big;
+                let x = x.clone();
+                move |a: &[u8], (b, c)| {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &x;} {
+                    let d = a.len() + b + c;
+                    println!("{x} {big:?} {{d}}", d = d + extra);
+                    big.len() + Some(x).map(|e| e + d).unwrap() + N
+                }}
             };
         }}
         .to_string()
@@ -460,23 +557,38 @@ fn all_but_weak_with_ret() {
 }
 
 #[test]
-fn weak() {
+fn non_move_values() {
     assert_eq!(
         with_closure(
             quote! {},
-            r#"fn f() {
-                #[closure(weak r, weak a)] move ||42;
+            r#"fn f(flag: bool, x: u8, s: String, v: Vec<u8>) {
+                #[closure(clone y)] || if flag { y } else { 0 };
+                #[closure(clone y)] || x == 5 && y == &x && assert_eq!(x, y);
+                #[closure(clone y)] || { let t: String = s; t.len() + y };
+                #[closure(clone y)] || { let n = v.len(); let v = n; v + y };
             }"#
             .parse()
             .unwrap()
         )
         .to_string(),
-        quote! {
-            fn f() {
+        quote! {fn f(flag: bool, x: u8, s: String, v: Vec<u8>) {
             {
-                let r = ::closure_attr::Downgrade::downgrade(&r);
-                let a = ::closure_attr::Downgrade::downgrade(&a);
-                move | |42
+                let y = y.clone();
+                move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &y;} if flag { y } else { 0 }}
+            };
+            {
+                let x = &x;
+                let y = y.clone();
+                move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &y;} *x == 5 && y == &*x && assert_eq!(*x, y)}
+            };
+            {
+                let y = y.clone();
+                move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &y;} { let t: String = s; t.len() + y }}
+            };
+            {
+                let v = &v;
+                let y = y.clone();
+                move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &y;} { let n = v.len(); let v = n; v + y }}
             };
         }}
         .to_string()
@@ -484,36 +596,29 @@ fn weak() {
 }
 
 #[test]
-fn upgrade() {
+fn non_move_macros() {
     assert_eq!(
         with_closure(
             quote! {},
             r#"fn f() {
-                #[closure(fail(7) r, fail({foo(); 9}) a, panic c)] move ||42;
+                #[closure(clone x)] || matches!(v.first(), Some(e) if *e > x);
+                #[closure(clone x)] || my_macro!(a => b.len(), x);
             }"#
             .parse()
             .unwrap()
         )
         .to_string(),
-        quote! {
-            fn f() {
+        quote! {fn f() {
             {
-                let r = ::closure_attr::Downgrade::downgrade(&r);
-                let a = ::closure_attr::Downgrade::downgrade(&a);
-                let c = ::closure_attr::Downgrade::downgrade(&c);
-                move | |
-                {
-                    let Some(r) = ::closure_attr::Upgrade::upgrade(&r) else {
-                        return 7;
-                    };
-                    let Some(a) = ::closure_attr::Upgrade::upgrade(&a) else {
-                        return {foo(); 9};
-                    };
-                    let Some(c) = ::closure_attr::Upgrade::upgrade(&c) else {
-                        ::std::panic!("Closure failed to upgrade weak pointer");
-                    };
-                    42
-                }
+                let v = &v;
+                let x = x.clone();
+                move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &x;} matches!(v.first(), Some(e) if *e > x)}
+            };
+            {
+                let a = &a;
+                let b = &b;
+                let x = x.clone();
+                move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &x;} my_macro!(a => b.len(), x)}
             };
         }}
         .to_string()
@@ -521,36 +626,43 @@ fn upgrade() {
 }
 
 #[test]
-fn upgrade_with_args() {
+fn non_move_mutates() {
     assert_eq!(
         with_closure(
             quote! {},
-            r#"fn f() {
-                #[closure(fail(7) r, fail({foo(); 9}) a, panic c)] move |a, b:i32, mut c|{return 42;};
+            r#"fn f(mut v: Vec<u8>, w: Vec<u8>) {
+                let mut n = 0;
+                let mut m = 0;
+                #[closure(clone x)] || {
+                    v.push(x);
+                    w.push(x);
+                    n += 1;
+                    m -= 1;
+                    let o = m?;
+                    std::mem::take(&mut n);
+                };
             }"#
             .parse()
             .unwrap()
         )
         .to_string(),
-        quote! {
-            fn f() {
+        quote! {fn f(mut v: Vec<u8>, w: Vec<u8>) {
+            let mut n = 0;
+            let mut m = 0;
             {
-                let r = ::closure_attr::Downgrade::downgrade(&r);
-                let a = ::closure_attr::Downgrade::downgrade(&a);
-                let c = ::closure_attr::Downgrade::downgrade(&c);
-                move |a, b:i32, mut c|
-                {
-                    let Some(r) = ::closure_attr::Upgrade::upgrade(&r) else {
-                        return 7;
-                    };
-                    let Some(a) = ::closure_attr::Upgrade::upgrade(&a) else {
-                        return {foo(); 9};
-                    };
-                    let Some(c) = ::closure_attr::Upgrade::upgrade(&c) else {
-                        ::std::panic!("Closure failed to upgrade weak pointer");
-                    };
-                    {return 42;}
-                }
+                let v = &mut v;
+                let w = &w;
+                let n = &mut n;
+                let m = &mut m;
+                let x = x.clone();
+                move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &x;} {
+                    v.push(x);
+                    w.push(x);
+                    *n += 1;
+                    *m -= 1;
+                    let o = (*m)?;
+                    std::mem::take(&mut *n);
+                }}
             };
         }}
         .to_string()
@@ -558,12 +670,750 @@ fn upgrade_with_args() {
 }
 
 #[test]
-fn embedded_closure() {
+fn assert() {
     assert_eq!(
         with_closure(
             quote! {},
             r#"fn f() {
-                #[closure(clone i)]
+                #[closure(clone x, assert(Send + 'static), assert(Fn(&str) -> usize))] move |s| x + s.len();
+                #[closure(assert(FnOnce()))] || ();
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {fn f() {
+            {
+                let x = x.clone();
+                fn assert_closure<F: Send + 'static + Fn(&str) -> usize>(f: F) -> F {
+                    f
+                }
+                assert_closure(move |s| {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &x;} x + s.len()})
+            };
+            {
+                fn assert_closure<F: FnOnce()>(f: F) -> F {
+                    f
+                }
+                assert_closure(| | ())
+            };
+        }}
+        .to_string()
+    );
+
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f<'a, T>(t: T) {
+                #[closure(clone t, assert(Fn() -> T + Send + 'a))] move || t;
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {fn f<'a, T>(t: T) {
+            {
+                let t = t.clone();
+                fn assert_closure<F: Send>(f: F) -> F {
+                    f
+                }
+                let closure = assert_closure(move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &t;} t});
+                let _: &dyn Fn() -> T = &closure;
+                let _: &(dyn ::closure_attr::__private::Anything + 'a) = &closure;
+                closure
+            };
+        }}
+        .to_string()
+    );
+
+    assert_eq!(
+        annotate_errors(with_closure(
+            quote! {set s = (assert(Send))},
+            r#"fn f() {
+                #[closure(assert(Send Sync))] move || ();
+            }"#
+            .parse()
+            .unwrap()
+        )),
+        quote! {
+            compile_error!{ (1usize,0usize), (1usize,0usize), "assert only applies to the closure it's written on" }
+            compile_error!{ (2usize,38usize), (2usize,42usize), "expected `+`" }
+            fn f() {move | | ();}
+        }
+        .to_string()
+    );
+}
+
+#[test]
+fn option_errors() {
+    // Valid captures and options are kept around malformed ones.
+    assert_eq!(
+        annotate_errors(with_closure(
+            r#"set s = (clon a, clone b), bogus, qualified_clone"#.parse().unwrap(),
+            r#"fn f() {
+                #[closure(use s)] move || ();
+            }"#
+            .parse()
+            .unwrap(),
+        )),
+        quote! {
+            compile_error!{ (1usize,9usize), (1usize,13usize), "expected clone, clone mut, ref, ref mut, move, move mut, weak, upgrade, fail, panic, enter, using, to_owned, into, via, try_clone, or share (2)" }
+            compile_error!{ (1usize,9usize), (1usize,13usize), "did you mean `clone`?" }
+            compile_error!{ (1usize,27usize), (1usize,32usize), "expected crate, qualified_clone, auto_move, set, or default" }
+            fn f() {
+                {
+                    let b = ::core::clone::Clone::clone(&b);
+                    move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break;let _ = &b;} ()}
+                };
+            }
+        }
+        .to_string()
+    );
+}
+
+#[test]
+fn capture_sets() {
+    assert_eq!(
+        with_closure(
+            quote! {set ui = (weak w, clone s), set all = (use ui, ref r)},
+            r#"fn f() {
+                #[closure(use ui, clone e)] move ||();
+                #[closure(use all)] move ||();
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {fn f() {
+            {
+                let w = ::closure_attr::__private::downgrade(&w);
+                let s = s.clone();
+                let e = e.clone();
+                move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break; let _=&s; let _=&e;} ()}
+            };
+            {
+                let w = ::closure_attr::__private::downgrade(&w);
+                let s = s.clone();
+                let r = &r;
+                move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break; let _=&s; let _=&r;} ()}
+            };
+        }}
+        .to_string()
+    );
+}
+
+#[test]
+fn defaults() {
+    assert_eq!(
+        with_closure(
+            quote! {set s = (ref r), default(weak w, use s, clone (a, b) = p)},
+            r#"fn f() {
+                #[closure()] move ||();
+                #[closure(clone w, move b)] move ||();
+                move ||();
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {fn f() {
+            {
+                let w = ::closure_attr::__private::downgrade(&w);
+                let r = &r;
+                let (a, b) = p.clone();
+                move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break; let _=&r; let _=&a; let _=&b;} ()}
+            };
+            {
+                let r = &r;
+                let w = w.clone();
+                let b = b;
+                move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break; let _=&r; let _=&w; let _=&b;} ()}
+            };
+            move | | ();
+        }}
+        .to_string()
+    );
+}
+
+#[test]
+fn crate_path() {
+    let expected = quote! {fn f() {
+        {
+            let s = facade::closure_attr::Share::share(&s);
+            let w = facade::closure_attr::__private::downgrade(&w);
+            move | | {
+                let Some(w) = facade::closure_attr::__private::upgrade(&w) else {
+                    return;
+                };
+                {#[allow(unreachable_code, clippy::never_loop)]loop{break; let _=&s;} ()}
+            }
+        };
+    }}
+    .to_string();
+    for attr in [
+        quote! {crate = "facade::closure_attr"},
+        quote! {crate = facade::closure_attr},
+    ] {
+        assert_eq!(
+            with_closure(
+                attr,
+                r#"fn f() {
+                    #[closure(share s, fail(()) w)] move ||();
+                }"#
+                .parse()
+                .unwrap()
+            )
+            .to_string(),
+            expected
+        );
+    }
+}
+
+#[test]
+fn share() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                #[closure(share s)] move ||();
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {fn f() {
+            {
+                let s = ::closure_attr::Share::share(&s);
+                move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break; let _=&s;} ()}
+            };
+        }}
+        .to_string()
+    );
+}
+
+#[test]
+fn fn_in_mod() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"mod m{ fn f() {
+                #[closure(clone c)] move ||();
+            }}"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {mod m{
+            fn f() {
+                {
+                    let c = c.clone();
+                    move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break; let _=&c;} ()}
+                };
+            }
+        }}
+        .to_string()
+    );
+}
+
+#[test]
+fn closure_in_var() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                let clos = #[closure(clone c)] move ||();
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {
+            fn f()
+            {
+                let clos = {
+                    let c = c.clone();
+                    move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break; let _=&c;} ()}
+                };
+            }
+        }
+        .to_string()
+    );
+}
+
+#[test]
+fn closure_in_call() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                callit(#[closure(clone c)] move ||());
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {fn f() {
+            callit({
+                let c = c.clone();
+                move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break; let _=&c;} ()}
+            });
+        }}
+        .to_string()
+    );
+}
+
+#[test]
+fn immediate_call() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                (#[closure(clone c)] move ||())();
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {fn f() {
+            ({
+                let c = c.clone();
+                move | | {#[allow(unreachable_code, clippy::never_loop)]loop{break; let _=&c;} ()}
+            })();
+        }}
+        .to_string()
+    );
+}
+
+#[test]
+fn all_but_weak() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                #[closure(clone c, clone mut cm, ref r, ref mut rm, move m, move mut mm)] move ||();
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {fn f() {
+            {
+                let c = c.clone();
+                let mut cm = cm.clone();
+                let r = &r;
+                let rm = &mut rm;
+                let m = m;
+                let mut mm = mm;
+                move | | {
+                    #[allow(unreachable_code, clippy::never_loop)]
+                    loop {
+                        break;
+                        let _ = &c;
+                        let _ = &cm;
+                        let _ = &r;
+                        let _ = &rm;
+                        let _ = &m;
+                        let _ = &mm;
+                    }
+                    ()
+                }
+            };
+        }}
+        .to_string()
+    );
+}
+
+#[test]
+fn all_but_weak_with_args() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                #[closure(clone c, clone mut cm, ref r, ref mut rm, move m, move mut mm)] move |a, b:i32, mut d|();
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {fn f() {
+            {
+                let c = c.clone();
+                let mut cm = cm.clone();
+                let r = &r;
+                let rm = &mut rm;
+                let m = m;
+                let mut mm = mm;
+                move |a, b:i32, mut d| {
+                    #[allow(unreachable_code, clippy::never_loop)]
+                    loop {
+                        break;
+                        let _ = &c;
+                        let _ = &cm;
+                        let _ = &r;
+                        let _ = &rm;
+                        let _ = &m;
+                        let _ = &mm;
+                    }
+                    ()
+                }
+            };
+        }}
+        .to_string()
+    );
+}
+
+#[test]
+fn all_but_weak_with_ret() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                #[closure(clone c, clone mut cm, ref r, ref mut rm, move m, move mut mm)] move |a, b:i32, mut d| {return 7;};
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {fn f() {
+            {
+                let c = c.clone();
+                let mut cm = cm.clone();
+                let r = &r;
+                let rm = &mut rm;
+                let m = m;
+                let mut mm = mm;
+                move |a, b:i32, mut d| {
+                    #[allow(unreachable_code, clippy::never_loop)]
+                    loop {
+                        break;
+                        let _ = &c;
+                        let _ = &cm;
+                        let _ = &r;
+                        let _ = &rm;
+                        let _ = &m;
+                        let _ = &mm;
+                    }
+                    { return 7; }
+                }
+            };
+        }}
+        .to_string()
+    );
+}
+
+#[test]
+fn conversions() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                #[closure(to_owned s, into p: PathBuf, into q, via(Rc::clone) r)] move ||();
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {fn f() {
+            {
+                let s = {
+                    use ::closure_attr::__private::ToOwned as _;
+                    s.to_owned()
+                };
+                let p: PathBuf = ::core::convert::Into::into(p);
+                let q = ::core::convert::Into::into(q);
+                let r = Rc::clone(&r);
+                move | | {
+                    #[allow(unreachable_code, clippy::never_loop)]
+                    loop {
+                        break;
+                        let _ = &s;
+                        let _ = &p;
+                        let _ = &q;
+                        let _ = &r;
+                    }
+                    ()
+                }
+            };
+        }}
+        .to_string()
+    );
+}
+
+#[test]
+fn try_clone() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                #[closure(try_clone(?) a, try_clone(return None) mut b)] move ||();
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {fn f() {
+            {
+                let a = a.try_clone()?;
+                let mut b = match b.try_clone() {
+                    ::core::result::Result::Ok(value) => value,
+                    ::core::result::Result::Err(_) => return None,
+                };
+                move | | {
+                    #[allow(unreachable_code, clippy::never_loop)]
+                    loop {
+                        break;
+                        let _ = &a;
+                        let _ = &b;
+                    }
+                    ()
+                }
+            };
+        }}
+        .to_string()
+    );
+
+    // The fail expression goes in the match arm as written.
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                #[closure(try_clone({ log(); return None }) a)] move ||();
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {fn f() {
+            {
+                let a = match a.try_clone() {
+                    ::core::result::Result::Ok(value) => value,
+                    ::core::result::Result::Err(_) => { log(); return None },
+                };
+                move | | {
+                    #[allow(unreachable_code, clippy::never_loop)]
+                    loop {
+                        break;
+                        let _ = &a;
+                    }
+                    ()
+                }
+            };
+        }}
+        .to_string()
+    );
+}
+
+#[test]
+fn destructure() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                #[closure(clone (a, b) = pair, move mut Point { x, y: (y, _) } = p, ref r = *s)] move ||();
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {fn f() {
+            {
+                let (a, b) = pair.clone();
+                let Point { mut x, y: (mut y, _) } = p;
+                let r = &(*s);
+                move | | {
+                    #[allow(unreachable_code, clippy::never_loop)]
+                    loop {
+                        break;
+                        let _ = &a;
+                        let _ = &b;
+                        let _ = &x;
+                        let _ = &y;
+                        let _ = &r;
+                    }
+                    ()
+                }
+            };
+        }}
+        .to_string()
+    );
+}
+
+#[test]
+fn destructure_upgrade() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                #[closure(weak (a, b) = pair, fail(7) S { c, .. } = s.t)] move ||();
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {fn f() {
+            {
+                let (a, b) = &pair;
+                let a = ::closure_attr::__private::downgrade(a);
+                let b = ::closure_attr::__private::downgrade(b);
+                let S { c, .. } = &s.t;
+                let c = ::closure_attr::__private::downgrade(c);
+                move | | {
+                    let Some(c) = ::closure_attr::__private::upgrade(&c) else {
+                        return 7;
+                    };
+                    ()
+                }
+            };
+        }}
+        .to_string()
+    );
+}
+
+#[test]
+fn weak() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                #[closure(weak r, weak a)] move ||42;
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {
+            fn f() {
+            {
+                let r = ::closure_attr::__private::downgrade(&r);
+                let a = ::closure_attr::__private::downgrade(&a);
+                move | |42
+            };
+        }}
+        .to_string()
+    );
+}
+
+#[test]
+fn upgrade() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                #[closure(fail(7) r, fail({foo(); 9}) a, panic c)] move ||42;
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {
+            fn f() {
+            {
+                let r = ::closure_attr::__private::downgrade(&r);
+                let a = ::closure_attr::__private::downgrade(&a);
+                let c = ::closure_attr::__private::downgrade(&c);
+                move | |
+                {
+                    let Some(r) = ::closure_attr::__private::upgrade(&r) else {
+                        return 7;
+                    };
+                    let Some(a) = ::closure_attr::__private::upgrade(&a) else {
+                        return {foo(); 9};
+                    };
+                    let Some(c) = ::closure_attr::__private::upgrade(&c) else {
+                        ::core::panic!("Closure failed to upgrade weak pointer");
+                    };
+                    42
+                }
+            };
+        }}
+        .to_string()
+    );
+}
+
+#[test]
+fn upgrade_with_args() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                #[closure(fail(7) r, fail({foo(); 9}) a, panic c)] move |x, b:i32, mut d|{return 42;};
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {
+            fn f() {
+            {
+                let r = ::closure_attr::__private::downgrade(&r);
+                let a = ::closure_attr::__private::downgrade(&a);
+                let c = ::closure_attr::__private::downgrade(&c);
+                move |x, b:i32, mut d|
+                {
+                    let Some(r) = ::closure_attr::__private::upgrade(&r) else {
+                        return 7;
+                    };
+                    let Some(a) = ::closure_attr::__private::upgrade(&a) else {
+                        return {foo(); 9};
+                    };
+                    let Some(c) = ::closure_attr::__private::upgrade(&c) else {
+                        ::core::panic!("Closure failed to upgrade weak pointer");
+                    };
+                    {return 42;}
+                }
+            };
+        }}
+        .to_string()
+    );
+}
+
+#[test]
+fn glib() {
+    let expand = |captures: &str| {
+        with_closure(
+            quote! {},
+            format!("fn f() {{ #[closure({captures})] move || 42; }}")
+                .parse()
+                .unwrap(),
+        )
+        .to_string()
+    };
+    assert_eq!(
+        expand("@strong a, @weak b, @weak-allow-none c, @to-owned d, @default-return 7"),
+        expand("clone a, fail(7) b, upgrade c, to_owned d"),
+    );
+    assert_eq!(
+        expand("@weak self as this, @strong self.x.0 as x"),
+        expand("fail(()) this = self, clone x = self.x.0"),
+    );
+    assert_eq!(
+        expand("upgrade c"),
+        quote! {
+            fn f() {
+            {
+                let c = ::closure_attr::__private::downgrade(&c);
+                move | |
+                {
+                    let c = ::closure_attr::__private::upgrade(&c);
+                    42
+                }
+            };
+        }}
+        .to_string()
+    );
+    assert!(expand("@weak self").contains("expected `as <name>`"));
+    assert!(expand("@strong a.b").contains("expected `as <name>`"));
+    assert!(expand("@weak a, @default-return 1, @default-return 2")
+        .contains("duplicate @default-return"));
+    assert!(expand("@watch a").contains("expected @strong, @weak"));
+}
+
+#[test]
+fn embedded_closure() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                #[closure(clone i)]
                 move || {
                     let inner = #[closure(clone i)]
                     move || {
@@ -607,3 +1457,299 @@ fn embedded_closure() {
         .to_string()
     );
 }
+
+#[test]
+fn enter() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                #[closure(enter(7) r, enter(8) mut m)] move ||42;
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {
+            fn f() {
+            {
+                move | |
+                {
+                    let Some(r) = ::closure_attr::Enter::enter(&r) else {
+                        return 7;
+                    };
+                    let Some(mut m) = ::closure_attr::Enter::enter(&m) else {
+                        return 8;
+                    };
+                    42
+                }
+            };
+        }}
+        .to_string()
+    );
+}
+
+#[test]
+fn using() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                #[closure(using(k::Kind) r, using(Kind<u8>, 7) mut m)] move ||42;
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {
+            fn f() {
+            {
+                let r = ::closure_attr::Using::<k::Kind, _>::prepare(&r);
+                let m = ::closure_attr::Using::<Kind<u8>, _>::prepare(&m);
+                move | |
+                {
+                    let r = match ::closure_attr::Using::enter(&r) {
+                        ::core::ops::ControlFlow::Continue(value) => value,
+                        ::core::ops::ControlFlow::Break(value) => return value,
+                    };
+                    let mut m = match ::closure_attr::Using::enter(&m) {
+                        ::core::ops::ControlFlow::Continue(value) => value,
+                        ::core::ops::ControlFlow::Break(_) => return 7,
+                    };
+                    42
+                }
+            };
+        }}
+        .to_string()
+    );
+}
+
+#[test]
+fn inherit() {
+    assert_eq!(
+        with_closure(
+            quote! {},
+            r#"fn f() {
+                #[closure(clone i, weak w, fail(7) f, ref mut m, ref r)]
+                move || {
+                    #[closure(inherit(except r), move i)]
+                    move || ();
+                };
+            }"#
+            .parse()
+            .unwrap()
+        )
+        .to_string(),
+        quote! {fn f() {
+            {
+                let i = i.clone();
+                let w = ::closure_attr::__private::downgrade(&w);
+                let f = ::closure_attr::__private::downgrade(&f);
+                let m = &mut m;
+                let r = &r;
+                move | | {
+                    let Some(f) = ::closure_attr::__private::upgrade(&f) else {
+                        return 7;
+                    };
+                    {
+                        #[allow(unreachable_code, clippy::never_loop)]
+                        loop {
+                            break;
+                            let _ = &i;
+                            let _ = &m;
+                            let _ = &r;
+                        }
+                        {
+                            {
+                                let w = w.clone();
+                                let f = ::closure_attr::__private::downgrade(&f);
+                                let m = &mut (*m);
+                                let i = i;
+                                move | | {
+                                    let Some(f) = ::closure_attr::__private::upgrade(&f) else {
+                                        return 7;
+                                    };
+                                    {
+                                        #[allow(unreachable_code, clippy::never_loop)]
+                                        loop {
+                                            break;
+                                            let _ = &w;
+                                            let _ = &m;
+                                            let _ = &i;
+                                        }
+                                        ()
+                                    }
+                                }
+                            };
+                        }
+                    }
+                }
+            };
+        }}
+        .to_string()
+    );
+
+    assert_eq!(
+        annotate_errors(with_closure(
+            quote! {},
+            r#"fn f() {
+                #[closure(inherit)] move ||();
+            }"#.parse().unwrap()
+        )),
+        quote! {
+            compile_error!{ (2usize,26usize), (2usize,33usize), "inherit requires an enclosing #[closure]" }
+            fn f() {move | |();}
+        }
+        .to_string()
+    );
+
+    // A try_clone's fail expression would return from the outer closure.
+    assert!(annotate_errors(with_closure(
+        quote! {},
+        r#"fn f() {
+                #[closure(try_clone(?) h)] move || #[closure(inherit)] move || h;
+            }"#
+        .parse()
+        .unwrap()
+    ))
+    .contains("cannot inherit `h`; capture it explicitly or add it to except"));
+
+    // An upgrade's `Option` holds a strong pointer.
+    assert!(annotate_errors(with_closure(
+        quote! {},
+        r#"fn f() {
+                #[closure(upgrade u)] move || #[closure(inherit)] move || u;
+            }"#
+        .parse()
+        .unwrap()
+    ))
+    .contains("cannot inherit `u`; capture it explicitly or add it to except"));
+}
+
+#[test]
+fn capture_spec() {
+    for src in [
+        "clone mut a",
+        "ref mut (a, b) = pair",
+        "into a : Box<str>",
+        "try_clone (?) mut a",
+        "using (Kind, 7) mut a",
+        "via (Rc::clone) a",
+    ] {
+        let capture = syn::parse_str::<CaptureSpec>(src).unwrap();
+        assert_eq!(
+            quote! {#capture}.to_string(),
+            src.parse::<TokenStream>().unwrap().to_string()
+        );
+    }
+
+    let capture = CaptureSpec::destructure(
+        CaptureKind::Fail(Box::new(syn::parse_quote!(7))),
+        syn::parse_quote!((a, b)),
+        syn::parse_quote!(pair),
+    );
+    assert_eq!(quote! {#capture}.to_string(), "fail (7) (a , b) = pair");
+    assert_eq!(capture.idents(), ["a", "b"]);
+}
+
+#[test]
+fn transform() {
+    let mut closure = syn::parse_quote!(move || a + b);
+    let captures = [
+        CaptureSpec::new(CaptureKind::Clone, syn::parse_quote!(a)),
+        CaptureSpec::new(CaptureKind::Panic, syn::parse_quote!(b)),
+    ];
+    let expr = transform_closure(&mut closure, &captures).unwrap();
+    assert_eq!(
+        quote! {#expr}.to_string(),
+        quote! {{
+            let a = a.clone();
+            let b = ::closure_attr::__private::downgrade(&b);
+            move | | {
+                let Some(b) = ::closure_attr::__private::upgrade(&b) else {
+                    ::core::panic!("Closure failed to upgrade weak pointer");
+                };
+                {
+                    #[allow(unreachable_code, clippy::never_loop)]
+                    loop {
+                        break;
+                        let _ = &a;
+                    }
+                    a + b
+                }
+            }
+        }}
+        .to_string()
+    );
+
+    // Nested closures are expanded and may inherit.
+    let mut closure = syn::parse_quote!(move || #[closure(inherit)]
+    move || a);
+    let expr = transform_closure(&mut closure, &captures[..1]).unwrap();
+    assert!(quote! {#expr}
+        .to_string()
+        .contains("let a = a . clone () ;"));
+    assert!(!quote! {#expr}.to_string().contains("# [closure"));
+
+    // Non-move closures borrow what they don't capture.
+    let mut closure = syn::parse_quote!(|| a + b.len());
+    let expr = transform_closure(&mut closure, &captures[..1]).unwrap();
+    assert!(quote! {#expr}.to_string().contains("let b = & b ;"));
+}
+
+#[test]
+fn closure_visitor() {
+    use syn::visit_mut::VisitMut;
+
+    // Another pass which embeds the visitor.
+    struct Pass {
+        closures: ClosureVisitor,
+        closures_seen: usize,
+    }
+
+    impl VisitMut for Pass {
+        fn visit_expr_mut(&mut self, expr: &mut syn::Expr) {
+            if let syn::Expr::Closure(_) = expr {
+                self.closures_seen += 1;
+            }
+            self.closures.visit_expr_mut(expr);
+        }
+    }
+
+    let mut pass = Pass {
+        closures: ClosureVisitor::with_args(quote! {qualified_clone})
+            .unwrap()
+            .crate_path(syn::parse_quote!(facade::closure_attr)),
+        closures_seen: 0,
+    };
+    let mut block: syn::Block = syn::parse_quote!({
+        let f = #[closure(clone a, weak b)]
+        move || ();
+        #[closure]
+        move || ();
+    });
+    pass.visit_block_mut(&mut block);
+    assert_eq!(pass.closures_seen, 2);
+    assert_eq!(
+        quote! {#block}.to_string(),
+        quote! {{
+            let f = {
+                let a = ::core::clone::Clone::clone(&a);
+                let b = facade::closure_attr::__private::downgrade(&b);
+                move | | {
+                    #[allow(unreachable_code, clippy::never_loop)]
+                    loop {
+                        break;
+                        let _ = &a;
+                    }
+                    ()
+                }
+            };
+            move | | ();
+        }}
+        .to_string()
+    );
+    let e = pass.closures.take_errors().unwrap();
+    assert_eq!(e.to_string(), "closure attribute must have arguments");
+    assert!(pass.closures.take_errors().is_none());
+}
@@ -6,3 +6,8 @@ use proc_macro::TokenStream;
 pub fn with_closure(attr: TokenStream, item: TokenStream) -> TokenStream {
     closure_attr_core::with_closure(attr.into(), item.into()).into()
 }
+
+#[proc_macro_attribute]
+pub fn closure(attr: TokenStream, item: TokenStream) -> TokenStream {
+    closure_attr_core::closure(attr.into(), item.into()).into()
+}
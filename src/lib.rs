@@ -1,10 +1,56 @@
 #![doc = include_str!("../README.md")]
+#![no_std]
+
+extern crate alloc;
+
+use alloc::rc;
+#[cfg(target_has_atomic = "ptr")]
+use alloc::sync;
+use core::{marker::PhantomData, ops::ControlFlow};
+
+#[doc(hidden)]
+pub mod __private {
+    pub use alloc::borrow::ToOwned;
+
+    use crate::{Downgrade, Upgrade};
+
+    /// Like [Downgrade::downgrade], but without a projection in the return
+    /// type, so an unimplemented `Downgrade` is a single error.
+    pub fn downgrade<T: Downgrade<Target = W>, W>(this: &T) -> W {
+        Downgrade::downgrade(this)
+    }
+
+    /// Like [Upgrade::upgrade], but without a projection in the return type,
+    /// so an unimplemented `Upgrade` is a single error.
+    pub fn upgrade<T: Upgrade<Target = S>, S>(this: &T) -> Option<S> {
+        Upgrade::upgrade(this)
+    }
+
+    /// Implemented by every type, so that converting to `dyn Anything + 'a`
+    /// only checks a lifetime.
+    pub trait Anything {}
+
+    impl<T: ?Sized> Anything for T {}
+}
 
 /// See the [crate-level documentation](index.html).
+#[cfg(feature = "macros")]
 pub use closure_attr_derive::with_closure;
 
+/// Marks a closure's captures. Only meaningful within a `#[with_closure]`
+/// item, which removes it; anywhere else, it reports the missing
+/// `#[with_closure]`.
+#[cfg(feature = "macros")]
+pub use closure_attr_derive::closure;
+
 /// Types which can be downgraded to their weak form,
-/// such as [`std::rc::Rc`] and [`std::sync::Arc`].
+/// such as [`alloc::rc::Rc`] and [`alloc::sync::Arc`].
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` can't be captured with `weak`, `upgrade`, `fail`, or `panic`",
+    label = "`{Self}` isn't a pointer with a weak form, such as `Rc` or `Arc`",
+    note = "these captures store a weak pointer, which needs an `Rc`, an `Arc`, or a type which implements `Downgrade`",
+    note = "use `clone` to capture a copy of the value instead, or wrap the value in an `Rc`"
+)]
 pub trait Downgrade: Sized {
     /// The weak form of Self.
     type Target: Upgrade<Target = Self>;
@@ -14,7 +60,12 @@ pub trait Downgrade: Sized {
 }
 
 /// Types which can be upgraded from their weak form,
-/// such as [`std::rc::Weak`] and [`std::sync::Weak`].
+/// such as [`alloc::rc::Weak`] and [`alloc::sync::Weak`].
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` can't be upgraded to a strong pointer",
+    label = "`{Self}` isn't a weak pointer, such as `rc::Weak` or `sync::Weak`",
+    note = "`fail`, `panic`, and `upgrade` upgrade the weak pointer a capture stores each time the closure runs"
+)]
 pub trait Upgrade {
     /// The strong form of Self.
     type Target: Downgrade;
@@ -23,30 +74,134 @@ pub trait Upgrade {
     fn upgrade(&self) -> Option<Self::Target>;
 }
 
-impl<T> Downgrade for std::rc::Rc<T> {
-    type Target = std::rc::Weak<T>;
+/// Types which are cheap to clone because cloning only copies or
+/// reference-counts a pointer, such as [`alloc::rc::Rc`], [`alloc::sync::Arc`],
+/// their weak forms, and references. Used by `share <ident>`; sharing any
+/// other type is a compile error.
+pub trait Share: Clone {
+    /// Clone the pointer.
+    fn share(this: &Self) -> Self {
+        Clone::clone(this)
+    }
+}
+
+impl<T: ?Sized> Share for rc::Rc<T> {}
+impl<T: ?Sized> Share for rc::Weak<T> {}
+#[cfg(target_has_atomic = "ptr")]
+impl<T: ?Sized> Share for sync::Arc<T> {}
+#[cfg(target_has_atomic = "ptr")]
+impl<T: ?Sized> Share for sync::Weak<T> {}
+impl<T: ?Sized> Share for &T {}
+
+/// Types which can be entered each time a closure runs, such as
+/// [`alloc::rc::Weak`] and [`alloc::sync::Weak`], which enter by upgrading.
+///
+/// Implement this to define your own call-time captures, e.g. a weak
+/// pointer which is upgraded and then locked.
+pub trait Enter {
+    /// The value the closure body sees. It may borrow from Self.
+    type Guard<'a>
+    where
+        Self: 'a;
+
+    /// Enter Self. Returns `None` if the closure body should be skipped.
+    fn enter(&self) -> Option<Self::Guard<'_>>;
+}
+
+/// User-defined capture kinds, used by `using(<kind>) <ident>`.
+///
+/// `prepare` runs when the closure is created; its result is stored in the
+/// closure. `enter` runs each time the closure is called; `Continue` binds
+/// its value to the captured name and `Break` returns early from the closure.
+pub trait Capture<T: ?Sized> {
+    /// The value stored in the closure.
+    type Stored;
+
+    /// The value the closure body sees. It may borrow from the stored value.
+    type Bound<'a>
+    where
+        T: 'a,
+        Self::Stored: 'a;
+
+    /// Returned from the closure when `enter` breaks, unless the capture
+    /// provides its own expression.
+    type Break;
+
+    /// Create the stored value when the closure is created.
+    fn prepare(value: &T) -> Self::Stored;
+
+    /// Produce the bound value each time the closure is called.
+    fn enter(stored: &Self::Stored) -> ControlFlow<Self::Break, Self::Bound<'_>>;
+}
+
+/// A value stored by a [Capture] kind. Used by generated code; it keeps
+/// track of the kind and the original type so `enter` can be resolved.
+#[doc(hidden)]
+pub struct Using<K: Capture<T>, T: ?Sized> {
+    stored: K::Stored,
+    marker: PhantomData<fn(&T) -> K>,
+}
+
+impl<K: Capture<T>, T: ?Sized> Using<K, T> {
+    pub fn prepare(value: &T) -> Self {
+        Using {
+            stored: K::prepare(value),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn enter(&self) -> ControlFlow<K::Break, K::Bound<'_>> {
+        K::enter(&self.stored)
+    }
+}
+
+impl<T> Downgrade for rc::Rc<T> {
+    type Target = rc::Weak<T>;
     fn downgrade(this: &Self) -> Self::Target {
-        std::rc::Rc::downgrade(this)
+        rc::Rc::downgrade(this)
     }
 }
 
-impl<T> Upgrade for std::rc::Weak<T> {
-    type Target = std::rc::Rc<T>;
+impl<T> Upgrade for rc::Weak<T> {
+    type Target = rc::Rc<T>;
     fn upgrade(&self) -> Option<Self::Target> {
         self.upgrade()
     }
 }
 
-impl<T> Downgrade for std::sync::Arc<T> {
-    type Target = std::sync::Weak<T>;
+impl<T> Enter for rc::Weak<T> {
+    type Guard<'a>
+        = rc::Rc<T>
+    where
+        Self: 'a;
+    fn enter(&self) -> Option<Self::Guard<'_>> {
+        self.upgrade()
+    }
+}
+
+#[cfg(target_has_atomic = "ptr")]
+impl<T> Downgrade for sync::Arc<T> {
+    type Target = sync::Weak<T>;
     fn downgrade(this: &Self) -> Self::Target {
-        std::sync::Arc::downgrade(this)
+        sync::Arc::downgrade(this)
     }
 }
 
-impl<T> Upgrade for std::sync::Weak<T> {
-    type Target = std::sync::Arc<T>;
+#[cfg(target_has_atomic = "ptr")]
+impl<T> Upgrade for sync::Weak<T> {
+    type Target = sync::Arc<T>;
     fn upgrade(&self) -> Option<Self::Target> {
         self.upgrade()
     }
 }
+
+#[cfg(target_has_atomic = "ptr")]
+impl<T> Enter for sync::Weak<T> {
+    type Guard<'a>
+        = sync::Arc<T>
+    where
+        Self: 'a;
+    fn enter(&self) -> Option<Self::Guard<'_>> {
+        self.upgrade()
+    }
+}
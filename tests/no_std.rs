@@ -0,0 +1,22 @@
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{rc::Rc, string::String, sync::Arc};
+
+#[test]
+#[closure_attr::with_closure]
+fn no_std_captures() {
+    let c = Rc::new(1);
+    let w = Rc::new(2);
+    let p = Arc::new(3);
+    let s = "4";
+    let callback = #[closure(clone c, fail(0) w, panic p, to_owned s)]
+    move || {
+        let s: &String = &s;
+        *c + *w + *p + s.parse::<i32>().unwrap()
+    };
+    assert_eq!(callback(), 10);
+    drop(w);
+    assert_eq!(callback(), 0);
+}
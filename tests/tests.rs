@@ -1,6 +1,7 @@
 use std::{
-    cell::{Cell, RefCell},
-    rc::Rc,
+    cell::{Cell, RefCell, RefMut},
+    ops::ControlFlow,
+    rc::{self, Rc},
     sync::Arc,
 };
 
@@ -89,6 +90,126 @@ fn dead_fail() {
     assert_eq!(callback(), 7);
 }
 
+#[test]
+#[closure_attr::with_closure]
+fn upgrade() {
+    let i = Arc::new(42);
+    let callback = #[closure(upgrade i)]
+    move || i.map(|i| *i);
+    assert_eq!(callback(), Some(42));
+    drop(i);
+    assert_eq!(callback(), None);
+}
+
+#[test]
+#[closure_attr::with_closure]
+fn non_move() {
+    let count = Rc::new(Cell::new(0));
+    let buffer = vec![1, 2, 3];
+    let callback = #[closure(clone count)]
+    || {
+        count.set(count.get() + 1);
+        let inner = #[closure(inherit)]
+        || buffer.iter().sum::<i32>() + count.get();
+        inner()
+    };
+    assert_eq!(callback(), 7);
+    assert_eq!(callback(), 8);
+    assert_eq!(buffer.len(), 3);
+    assert_eq!(Rc::strong_count(&count), 2);
+}
+
+#[test]
+#[closure_attr::with_closure]
+fn non_move_mutates() {
+    let count = Rc::new(Cell::new(1));
+    let values = vec![1, 2, 3];
+    let mut log = Vec::new();
+    let mut calls = 0;
+    let mut callback = #[closure(clone count)]
+    || {
+        if matches!(values.first(), Some(v) if *v > 0) {
+            log.push(count.get());
+        }
+        calls += 1;
+        count.set(count.get() * 2);
+    };
+    callback();
+    callback();
+    assert_eq!(log, [1, 2]);
+    assert_eq!(calls, 2);
+    assert_eq!(values.len(), 3);
+}
+
+#[test]
+#[closure_attr::with_closure]
+fn non_move_values() {
+    let count = Rc::new(Cell::new(1));
+    let flag = true;
+    let x = 5;
+    let values = vec![1, 2, 3];
+    let name = String::from("name");
+    let check = #[closure(clone count)]
+    || {
+        let n = values.len();
+        let values = n;
+        flag && x == 5 && values == 3 && count.get() == 1
+    };
+    assert!(check());
+    assert!(check());
+    let consume = #[closure(clone count)]
+    || {
+        let name: String = name;
+        name.len() + count.get()
+    };
+    assert_eq!(consume(), 5);
+    assert_eq!((flag, x, values.len()), (true, 5, 3));
+}
+
+#[test]
+#[closure_attr::with_closure]
+fn assert() {
+    let i = Arc::new(1);
+    let callback = #[closure(clone i, assert(Send + 'static + Fn(&str) -> usize))]
+    |s| *i + s.len();
+    assert_eq!(
+        std::thread::spawn(move || callback("ab")).join().unwrap(),
+        3
+    );
+}
+
+#[test]
+fn assert_generic() {
+    #[closure_attr::with_closure]
+    fn make<T: Clone + 'static>(t: T) -> impl Fn() -> T {
+        #[closure(clone t, assert(Fn() -> T + 'static))]
+        move || t.clone()
+    }
+
+    assert_eq!(make(String::from("t"))(), "t");
+}
+
+#[test]
+#[closure_attr::with_closure]
+fn glib() {
+    struct S {
+        x: Rc<String>,
+    }
+    let s = S {
+        x: Rc::new("x".into()),
+    };
+    let i = Arc::new(42);
+    let n = "n";
+    let callback = #[closure(@weak i, @strong s.x as x, @to-owned n, @default-return 7)]
+    move || {
+        let _: String = n;
+        *i + x.len() as i32
+    };
+    assert_eq!(callback(), 43);
+    drop(i);
+    assert_eq!(callback(), 7);
+}
+
 #[test]
 #[closure_attr::with_closure]
 #[allow(clippy::needless_return)]
@@ -115,6 +236,255 @@ fn dead_panic() {
     callback();
 }
 
+struct Shared<T>(Rc<RefCell<T>>);
+
+impl<T> closure_attr::Enter for Shared<T> {
+    type Guard<'a>
+        = RefMut<'a, T>
+    where
+        T: 'a;
+    fn enter(&self) -> Option<Self::Guard<'_>> {
+        self.0.try_borrow_mut().ok()
+    }
+}
+
+#[test]
+#[closure_attr::with_closure]
+fn live_enter() {
+    let i = Arc::new(42);
+    let weak = Arc::downgrade(&i);
+    let callback = #[closure(enter(7) weak)]
+    move || *weak;
+    assert_eq!(callback(), 42);
+    drop(i);
+    assert_eq!(callback(), 7);
+}
+
+#[test]
+#[closure_attr::with_closure]
+fn custom_enter() {
+    let cell = Rc::new(RefCell::new(1));
+    let shared = Shared(cell.clone());
+    let callback = #[closure(enter(0) mut shared)]
+    move || {
+        *shared += 1;
+        *shared
+    };
+    assert_eq!(callback(), 2);
+    let borrowed = cell.borrow();
+    assert_eq!(callback(), 0);
+    drop(borrowed);
+    assert_eq!(callback(), 3);
+}
+
+struct Upgraded;
+
+impl<T> closure_attr::Capture<Rc<T>> for Upgraded {
+    type Stored = rc::Weak<T>;
+    type Bound<'a>
+        = Rc<T>
+    where
+        T: 'a;
+    type Break = i32;
+    fn prepare(value: &Rc<T>) -> Self::Stored {
+        Rc::downgrade(value)
+    }
+    fn enter(stored: &Self::Stored) -> ControlFlow<i32, Rc<T>> {
+        match stored.upgrade() {
+            Some(value) => ControlFlow::Continue(value),
+            None => ControlFlow::Break(-1),
+        }
+    }
+}
+
+struct Borrowed;
+
+impl<T> closure_attr::Capture<Rc<RefCell<T>>> for Borrowed {
+    type Stored = Rc<RefCell<T>>;
+    type Bound<'a>
+        = RefMut<'a, T>
+    where
+        T: 'a;
+    type Break = ();
+    fn prepare(value: &Rc<RefCell<T>>) -> Self::Stored {
+        value.clone()
+    }
+    fn enter(stored: &Self::Stored) -> ControlFlow<(), RefMut<'_, T>> {
+        match stored.try_borrow_mut() {
+            Ok(value) => ControlFlow::Continue(value),
+            Err(_) => ControlFlow::Break(()),
+        }
+    }
+}
+
+#[test]
+#[closure_attr::with_closure]
+fn using_break() {
+    let i = Rc::new(42);
+    let callback = #[closure(using(Upgraded) i)]
+    move || *i;
+    let with_fail = #[closure(using(Upgraded, 7) i)]
+    move || *i;
+    assert_eq!(callback(), 42);
+    assert_eq!(with_fail(), 42);
+    drop(i);
+    assert_eq!(callback(), -1);
+    assert_eq!(with_fail(), 7);
+}
+
+#[test]
+#[closure_attr::with_closure]
+fn using_mut() {
+    let i = Rc::new(RefCell::new(1));
+    let callback = #[closure(using(Borrowed) mut i)]
+    move || *i += 1;
+    callback();
+    let borrowed = i.borrow();
+    callback();
+    drop(borrowed);
+    callback();
+    assert_eq!(*i.borrow(), 3);
+}
+
+#[test]
+#[closure_attr::with_closure]
+fn conversions() {
+    fn make(s: &str, p: &std::path::Path, n: u8) -> impl Fn() -> (String, std::path::PathBuf, u32) {
+        #[closure(to_owned s, via(std::path::Path::to_path_buf) p, into n: u32)]
+        move || (s.clone(), p.clone(), n)
+    }
+    let callback = make("a", std::path::Path::new("b"), 3);
+    assert_eq!(callback(), ("a".to_string(), "b".into(), 3));
+}
+
+#[test]
+#[closure_attr::with_closure]
+fn share() {
+    let i = Rc::new(42);
+    let callback = #[closure(share i)]
+    move || *i;
+    assert_eq!(Rc::strong_count(&i), 2);
+    assert_eq!(callback(), 42);
+}
+
+#[test]
+#[closure_attr::with_closure(qualified_clone)]
+fn qualified_clone() {
+    let i = Rc::new(42);
+    let callback = #[closure(clone i)]
+    move || *i;
+    assert_eq!(callback(), 42);
+}
+
+#[test]
+#[closure_attr::with_closure]
+fn destructure() {
+    struct Point {
+        x: Rc<i32>,
+        y: Rc<i32>,
+    }
+    let pair = (Rc::new(1), Rc::new(2));
+    let p = Point {
+        x: Rc::new(3),
+        y: Rc::new(4),
+    };
+    let callback = #[closure(clone (a, b) = pair, fail(0) Point { x, y } = p)]
+    move || *a + *b + *x + *y;
+    assert_eq!(callback(), 10);
+    drop(p);
+    assert_eq!(callback(), 0);
+    assert_eq!(Rc::strong_count(&pair.0), 2);
+}
+
+#[test]
+#[closure_attr::with_closure(set both = (clone a, weak b))]
+fn capture_sets() {
+    let a = Rc::new(1);
+    let b = Rc::new(2);
+    let c = Rc::new(3);
+    let callback = #[closure(use both, clone c)]
+    move || *a + *b.upgrade().unwrap() + *c;
+    assert_eq!(callback(), 6);
+    assert_eq!(Rc::strong_count(&b), 1);
+}
+
+#[test]
+#[closure_attr::with_closure(default(weak x))]
+fn default_captures() {
+    let x = Rc::new(1);
+    let y = Rc::new(2);
+    let weak = #[closure(clone y)]
+    move || *x.upgrade().unwrap() + *y;
+    let strong = #[closure(clone x)]
+    move || *x;
+    assert_eq!(weak(), 3);
+    assert_eq!(strong(), 1);
+    assert_eq!(Rc::strong_count(&x), 2);
+}
+
+mod facade {
+    pub use closure_attr;
+}
+
+#[test]
+#[closure_attr::with_closure(crate = "crate::facade::closure_attr")]
+fn crate_path() {
+    let i = Rc::new(42);
+    let callback = #[closure(fail(7) i)]
+    move || *i;
+    assert_eq!(callback(), 42);
+    drop(i);
+    assert_eq!(callback(), 7);
+}
+
+struct Handle(bool);
+
+impl Handle {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        if self.0 {
+            Ok(Handle(true))
+        } else {
+            Err(std::io::Error::other("can't clone"))
+        }
+    }
+}
+
+#[test]
+#[closure_attr::with_closure]
+fn try_clone() {
+    fn fail(h: &Handle) -> Option<impl Fn() -> bool> {
+        Some(
+            #[closure(try_clone(return None) h)]
+            move || h.0,
+        )
+    }
+    fn logged(h: &Handle, log: &Cell<u8>) -> Option<impl Fn() -> bool> {
+        Some(
+            #[closure(try_clone({ log.set(log.get() + 1); return None }) h)]
+            move || {
+                let inner = #[closure(try_clone({ return false }) h)]
+                move || h.0;
+                inner()
+            },
+        )
+    }
+    fn propagate(h: &Handle) -> std::io::Result<impl Fn() -> bool> {
+        Ok(
+            #[closure(try_clone(?) h)]
+            move || h.0,
+        )
+    }
+
+    assert!(fail(&Handle(true)).unwrap()());
+    assert!(fail(&Handle(false)).is_none());
+    let log = Cell::new(0);
+    assert!(logged(&Handle(true), &log).unwrap()());
+    assert!(logged(&Handle(false), &log).is_none());
+    assert_eq!(log.get(), 1);
+    assert!(propagate(&Handle(true)).unwrap()());
+    assert!(propagate(&Handle(false)).is_err());
+}
+
 #[test]
 #[closure_attr::with_closure]
 #[allow(clippy::needless_return)]
@@ -134,6 +504,26 @@ fn embedded_closure() {
     assert_eq!(*i, 42);
 }
 
+#[test]
+#[closure_attr::with_closure]
+fn inherit() {
+    let i = Rc::new(1);
+    let w = Rc::new(2);
+    let mut m = 0;
+    let mut callback = #[closure(clone i, weak w, ref mut m)]
+    move || {
+        let mut inner = #[closure(inherit)]
+        move || {
+            *m += *i + *w.upgrade().unwrap();
+        };
+        inner();
+    };
+    callback();
+    callback();
+    assert_eq!(m, 6);
+    assert_eq!(Rc::strong_count(&w), 1);
+}
+
 #[test]
 #[allow(clippy::no_effect)]
 fn capture_whole() {